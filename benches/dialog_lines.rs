@@ -0,0 +1,104 @@
+// Benchmarks formatting the per-crate lines that get printed during a pull, comparing borrowed
+// disps against owned ones. Only the formatting is measured, so nothing gets written out. Along
+// with the times it prints how many allocations each line takes. Set `DUMPSTA_BENCH_LINES` to
+// change how many lines it has
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    env,
+    hint::black_box,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use dumpsta::{dialog, disps};
+
+const DEFAULT_NUM_LINES: usize = 10_000;
+
+// Counts every allocation so that the disps can be compared by more than just time
+struct CountingAlloc;
+
+static NUM_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        NUM_ALLOCS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        NUM_ALLOCS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn num_lines() -> usize {
+    env::var("DUMPSTA_BENCH_LINES")
+        .ok()
+        .and_then(|num| num.parse().ok())
+        .unwrap_or(DEFAULT_NUM_LINES)
+}
+
+// A crate's name along with its dir
+type Crate = (String, PathBuf);
+
+// Each line is for a crate with a reasonably long name and path like the ones in the out dir
+fn fixture_crates(num_lines: usize) -> Vec<Crate> {
+    let out_dir = Path::new("/home/user/projects/dumpsta/snapshots");
+    (0..num_lines)
+        .map(|i| {
+            let name = format!("some-snapshot-tested-crate-{}-0.{}.0", i, i % 30);
+            let dir = out_dir.join(&name);
+            (name, dir)
+        })
+        .collect()
+}
+
+const LINE: &str = "Extracted {} snapshots from {} into {}";
+
+fn borrowed_lines(crates: &[Crate]) {
+    for (i, (name, dir)) in crates.iter().enumerate() {
+        let line = dialog::fmt_with(LINE, disps![i, name.as_str(), dir.as_path()]).unwrap();
+        black_box(line);
+    }
+}
+
+// How displaying things worked before `Disp` could borrow
+fn owned_lines(crates: &[Crate]) {
+    for (i, (name, dir)) in crates.iter().enumerate() {
+        let line = dialog::fmt_with(LINE, disps![i, name.clone(), dir.clone()]).unwrap();
+        black_box(line);
+    }
+}
+
+fn dialog_lines(c: &mut Criterion) {
+    let crates = fixture_crates(num_lines());
+    let mut group = c.benchmark_group("dialog_lines");
+    group.throughput(Throughput::Elements(crates.len() as u64));
+
+    let borrowed: fn(&[Crate]) = borrowed_lines;
+    for (name, run) in [("borrowed", borrowed), ("owned", owned_lines)] {
+        let before = NUM_ALLOCS.load(Ordering::Relaxed);
+        run(&crates);
+        let num_allocs = NUM_ALLOCS.load(Ordering::Relaxed) - before;
+        println!(
+            "dialog_lines/{}: {:.2} allocations per line",
+            name,
+            num_allocs as f64 / crates.len() as f64
+        );
+
+        group.bench_function(name, |b| b.iter(|| run(&crates)));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, dialog_lines);
+criterion_main!(benches);
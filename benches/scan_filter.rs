@@ -0,0 +1,81 @@
+// Benchmarks the dependency matching that runs on every version during an index scan. The index
+// is synthetic so that it runs offline. Set `DUMPSTA_BENCH_CRATES` to change how many crates it has
+use std::{env, hint::black_box};
+
+use crates_index::DependencyKind;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use dumpsta::{DepFilter, VersionExt};
+use rayon::prelude::*;
+
+const DEFAULT_NUM_CRATES: usize = 10_000;
+// Each crate has this many deps, like a typical crate pulling in a handful of common ones
+const DEPS_PER_CRATE: usize = 8;
+
+fn num_crates() -> usize {
+    env::var("DUMPSTA_BENCH_CRATES")
+        .ok()
+        .and_then(|num| num.parse().ok())
+        .unwrap_or(DEFAULT_NUM_CRATES)
+}
+
+// Roughly one in ten crates uses `insta`, and some of those rename it
+fn fixture_index(num_crates: usize) -> Vec<VersionExt> {
+    let cksum = "0".repeat(64);
+    (0..num_crates)
+        .map(|i| {
+            let mut deps: Vec<_> = (0..DEPS_PER_CRATE)
+                .map(|j| dep(&format!("dep-{}", (i + j) % 500), None, "normal"))
+                .collect();
+            match i % 40 {
+                0..=2 => deps.push(dep("insta", None, "dev")),
+                3 => deps.push(dep("snap", Some("insta"), "dev")),
+                _ => {}
+            }
+            let line = format!(
+                r#"{{"name":"crate-{}","vers":"0.1.0","deps":[{}],"cksum":"{}","features":{{}},"yanked":false}}"#,
+                i,
+                deps.join(","),
+                cksum,
+            );
+            VersionExt::new(serde_json::from_str(&line).unwrap())
+        })
+        .collect()
+}
+
+fn dep(name: &str, package: Option<&str>, kind: &str) -> String {
+    let package = package.map_or("null".to_owned(), |package| format!(r#""{}""#, package));
+    format!(
+        r#"{{"name":"{}","req":"^1","features":[],"package":{},"kind":"{}","target":null,"optional":false,"default_features":true}}"#,
+        name, package, kind,
+    )
+}
+
+fn scan_filter(c: &mut Criterion) {
+    let versions = fixture_index(num_crates());
+    let mut group = c.benchmark_group("scan_filter");
+    group.throughput(Throughput::Elements(versions.len() as u64));
+
+    let any_kind = DepFilter::any_kind("insta");
+    let dev_only = DepFilter {
+        kinds: vec![DependencyKind::Dev],
+        req: Some("^1.20".parse().unwrap()),
+        ..DepFilter::any_kind("insta")
+    };
+    for (name, filter) in [("any_kind", &any_kind), ("dev_with_req", &dev_only)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let num_matches = versions
+                    .par_iter()
+                    .filter(|version| filter.is_scanned(version.name()))
+                    .filter(|version| filter.uses_any(version).unwrap_or(false))
+                    .count();
+                black_box(num_matches)
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, scan_filter);
+criterion_main!(benches);
@@ -1,11 +1,600 @@
-use clap::Parser;
+use std::{
+    env,
+    io::{self, IsTerminal},
+    num::{NonZeroUsize, ParseIntError},
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use clap::{ArgEnum, CommandFactory, FromArgMatches, Parser, Subcommand};
+use crates_index::DependencyKind;
+use regex::Regex;
+use semver::VersionReq;
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::config::Config;
+
+// Parsed through `Cli` so that leaving out the subcommand runs `scan`. This only ever gets parsed
+// once, so the size difference between the variants doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+pub enum Args {
+    /// Find the crates that use the dependencies and pull their snapshots (the default)
+    Scan(ScanArgs),
+    /// Print the `name version` of each matching crate to stdout without downloading anything
+    List(ListArgs),
+    /// Summarize how the matching crates depend on the deps without downloading anything
+    Stats(CommonArgs),
+    /// Pull the snapshots from a single crate without scanning the index
+    Crate(CrateArgs),
+}
+
+impl Args {
+    // Also fills in anything that wasn't passed from the config file
+    pub fn parse() -> Result<Self> {
+        let matches = Cli::command().get_matches();
+        let Cli { scan, command } = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        let (mut args, matches) = match (command, matches.subcommand()) {
+            (Some(command), Some((_, sub_matches))) => (command, sub_matches),
+            _ => (Self::Scan(scan), &matches),
+        };
+
+        let common = args.common();
+        if !common.no_config {
+            let config = Config::load(common.config.as_ref())?;
+            match &mut args {
+                Self::Scan(scan) | Self::Crate(CrateArgs { scan, .. }) => {
+                    config.apply_scan(scan, matches)
+                }
+                Self::List(ListArgs { common, .. }) | Self::Stats(common) => {
+                    config.apply_common(common, matches)
+                }
+            }
+        }
+
+        Ok(args)
+    }
+
+    fn common(&self) -> &CommonArgs {
+        match self {
+            Self::Scan(scan) | Self::Crate(CrateArgs { scan, .. }) => &scan.common,
+            Self::List(ListArgs { common, .. }) | Self::Stats(common) => common,
+        }
+    }
+}
+
+const EXIT_CODES: &str = "\
+EXIT CODES:
+      0    Everything succeeded
+      1    Something went wrong outside of pulling an individual crate
+      2    Some crates failed to download or extract
+    130    Interrupted";
 
 #[derive(Parser)]
-pub struct Args {
+#[clap(args_conflicts_with_subcommands = true, after_help = EXIT_CODES)]
+struct Cli {
+    #[clap(flatten)]
+    scan: ScanArgs,
+    #[clap(subcommand)]
+    command: Option<Args>,
+}
+
+// Options for scanning the index that every subcommand shares
+#[derive(clap::Args)]
+pub struct CommonArgs {
+    /// Number of threads used to scan the index. `0` uses one per CPU [Default: NUM_CPUS]
+    #[clap(
+        short = 't',
+        long,
+        value_name = "N",
+        default_value = "0",
+        hide_default_value = true
+    )]
+    pub scan_threads: Threads,
+    /// Deprecated alias for `--scan-threads`
+    #[clap(long, value_name = "N", hide = true, conflicts_with = "scan-threads")]
+    pub threads: Option<Threads>,
+    /// Name of a dependency to find reverse-dependencies for. Can be passed multiple times
+    #[clap(long = "dep", value_name = "NAME", default_value = "insta")]
+    pub deps: Vec<String>,
+    /// Which versions of each crate get checked. `lowest` finds the earliest version that used
+    /// the dependency, and `all` takes every version that uses it
+    #[clap(long, arg_enum, value_name = "STRATEGY", default_value = "highest")]
+    pub version_select: VersionSelect,
+    /// Deprecated alias for `--version-select all`
+    #[clap(long, hide = true, conflicts_with = "version-select")]
+    pub all_versions: bool,
+    /// Consider pre-release versions when picking the highest version and when matching
+    /// `--dep-req`
+    #[clap(long)]
+    pub include_pre: bool,
+    /// How yanked versions are handled. `skip` falls back to the highest version that wasn't yanked
+    #[clap(long, arg_enum, value_name = "POLICY", default_value = "skip")]
+    pub yank_policy: YankPolicy,
+    /// Only match crates whose requirement on the dependency can overlap with this one
+    #[clap(long, value_name = "VERSIONREQ")]
+    pub dep_req: Option<VersionReq>,
+    /// Which kinds of dependencies are considered when matching
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "KINDS",
+        use_value_delimiter = true,
+        default_value = "normal,dev,build"
+    )]
+    pub kinds: Vec<DepKind>,
+    /// Whether optional dependencies are considered when matching
+    #[clap(long, parse(try_from_str), default_value_t = true, value_name = "BOOL")]
+    pub include_optional: bool,
+    /// Only match dependencies that are gated to this target triple
+    #[clap(long, value_name = "TRIPLE", parse(try_from_str = parse_target))]
+    pub only_target: Option<String>,
+    /// Only scan crates whose names match this regex
+    #[clap(long, value_name = "REGEX")]
+    pub crate_filter: Option<Regex>,
+    /// Output format for the results. `json` is printed to stdout, where `scan` follows the
+    /// matched crates with the run summary on its own line
+    #[clap(long, arg_enum, default_value = "pretty")]
+    pub format: Format,
+    /// Only display warnings, errors, and the final summary
+    #[clap(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Display extra details while running
+    #[clap(short, long)]
+    pub verbose: bool,
+    /// Log internal events at this level or above (e.g. `debug`). Overrides `RUST_LOG`
+    #[clap(long, value_name = "LEVEL")]
+    pub log_level: Option<LevelFilter>,
+    /// When to use colored output. `auto` respects `NO_COLOR`
+    #[clap(long, arg_enum, value_name = "WHEN", default_value = "auto")]
+    pub color: ColorChoice,
+    /// The git URL of an index to use instead of crates.io's
+    #[clap(long, value_name = "URL")]
+    pub index_url: Option<String>,
+    /// An existing index checkout to scan instead of fetching one (e.g. a mirror's)
+    #[clap(long, value_name = "PATH", conflicts_with = "index-url")]
+    pub index_path: Option<PathBuf>,
+    /// Update the index and rescan it even if a recent scan exists
+    #[clap(long)]
+    pub force_update_index: bool,
+    /// Minimum time between forced index updates
+    #[clap(
+        long,
+        value_name = "DURATION",
+        default_value = "5m",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    pub force_update_interval: Duration,
+    /// How long the results of a previous scan can be reused for (e.g. `30m`, `1day`)
+    #[clap(
+        long,
+        value_name = "DURATION",
+        default_value = "1h",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    pub max_age: Duration,
+    /// Where the results of a scan get cached between runs [Default: CACHE_DIR/dumpsta/scan.json]
+    #[clap(long, value_name = "PATH")]
+    pub cache_scan: Option<PathBuf>,
+    /// Rescan the index even if the cached scan is still fresh
+    #[clap(long)]
+    pub refresh: bool,
+    /// Read default flags from this file instead of `./dumpsta.toml` or
+    /// `$XDG_CONFIG_HOME/dumpsta/dumpsta.toml`
+    #[clap(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Ignore any config file
+    #[clap(long, conflicts_with = "config")]
+    pub no_config: bool,
+}
+
+#[derive(clap::Args)]
+pub struct ScanArgs {
+    #[clap(flatten)]
+    pub common: CommonArgs,
     /// Check how many crates would be downloaded without downloading
     #[clap(short, long)]
     pub dry_run: bool,
-    /// Number of threads used to scan the index [Default: NUM_CPUS]
-    #[clap(short, long, default_value_t = 0, hide_default_value = true)]
-    pub threads: usize,
+    /// Compare the matching crates against a previous run's scan cache, `--format json` output,
+    /// or manifest. The diff is also printed as JSON with `--format json`
+    #[clap(long, value_name = "PREV", requires = "dry-run")]
+    pub diff: Option<PathBuf>,
+    /// Where snapshot files get extracted to [Default: ./dumpsta-out]
+    #[clap(long, value_name = "PATH")]
+    pub out_dir: Option<PathBuf>,
+    /// Which files count as snapshots, matched against the path within the crate. Can be passed
+    /// multiple times
+    #[clap(
+        long,
+        value_name = "PATTERN",
+        default_values = dumpsta::DEFAULT_SNAPSHOT_GLOBS,
+        parse(try_from_str = parse_snapshot_glob)
+    )]
+    pub snapshot_glob: Vec<String>,
+    /// Only keep the snapshots with a line matching this regex, displaying where each one is
+    #[clap(long, value_name = "REGEX")]
+    pub grep: Option<regex::bytes::Regex>,
+    /// Also copy every snapshot into this one flat directory as `name-version__path_to_file.snap`
+    #[clap(long, value_name = "PATH")]
+    pub collect_dir: Option<PathBuf>,
+    /// Number of times a failed download request is retried
+    #[clap(long, value_name = "N", default_value_t = 2)]
+    pub retries: usize,
+    /// Download at most this many crates
+    #[clap(long, value_name = "N")]
+    pub limit: Option<usize>,
+    /// Skip crates whose `.crate` is bigger than this (e.g. `50MB` or `1GiB`). The index doesn't
+    /// have sizes, so crates not yet in the crate dir get stopped partway through downloading
+    #[clap(long, value_name = "SIZE", parse(try_from_str = parse_size))]
+    pub max_crate_size: Option<u64>,
+    /// The order crates get downloaded in, which also decides which ones `--limit` keeps.
+    /// `version` puts the most recently published versions of each crate first going by the
+    /// index. `downloads` and `recent` put the most downloaded or most recently published first
+    /// and need `--with-crates-io-meta`
+    #[clap(long, arg_enum, value_name = "ORDER", default_value = "name")]
+    pub sort: SortChoice,
+    /// Delay between download requests. Values below the crawling policy's minimum of 1000ms get
+    /// raised to it unless the downloads come from somewhere other than crates.io
+    #[clap(long, value_name = "MILLIS", default_value_t = 1_000)]
+    pub request_delay: u64,
+    /// User agent used for downloads. The crawling policy requires it to be descriptive
+    #[clap(
+        long,
+        value_name = "STRING",
+        default_value = "dumpsta (github.com/LovecraftianHorror/dumpsta)",
+        parse(try_from_str = parse_user_agent)
+    )]
+    pub user_agent: String,
+    /// Stop starting new downloads once the whole run has taken this long. The crates in flight
+    /// still finish, and running again picks up where it left off
+    #[clap(long, value_name = "SECS")]
+    pub timeout_total: Option<u64>,
+    /// Timeout for connecting to the download server
+    #[clap(long, value_name = "SECS", default_value_t = 30)]
+    pub connect_timeout: u64,
+    /// Timeout for reading from an established download connection
+    #[clap(long, value_name = "SECS", default_value_t = 30)]
+    pub read_timeout: u64,
+    /// Only search the already downloaded crates for snapshots without touching the network
+    #[clap(long)]
+    pub offline: bool,
+    /// Download crates from this mirror instead of where the index points (e.g.
+    /// `https://mirror.example.com/api/v1/crates`). Downloads are still verified against the
+    /// index's checksums
+    #[clap(long, value_name = "BASE", parse(try_from_str = parse_registry_url))]
+    pub registry_url: Option<String>,
+    /// The registry dir under `$CARGO_HOME/registry` (or `$DUMPSTA_REGISTRY` when set) to use,
+    /// preferring the sparse index by default
+    #[clap(long, value_name = "NAME")]
+    pub registry: Option<String>,
+    /// How many crates to download and extract at once. Requests still respect the request delay
+    #[clap(
+        short = 'j',
+        long,
+        alias = "jobs",
+        value_name = "N",
+        default_value_t = 4,
+        parse(try_from_str = parse_jobs)
+    )]
+    pub download_jobs: usize,
+    /// Where `.crate`s are reused from and kept. Using a dir outside of cargo's cache keeps it
+    /// untouched, and the dir can be wiped at any time [Default: cargo's registry cache]
+    #[clap(long, value_name = "PATH")]
+    pub crate_dir: Option<PathBuf>,
+    /// Keep the `.crate`s in the crate dir so later runs can reuse them (the default)
+    #[clap(long, overrides_with = "no-keep-crate")]
+    pub keep_crate: bool,
+    /// Don't keep any `.crate`s around after extracting them
+    #[clap(long, overrides_with = "keep-crate")]
+    pub no_keep_crate: bool,
+    /// Write a JSON lines listing of every extracted snapshot to this file
+    #[clap(long, value_name = "PATH")]
+    pub manifest: Option<PathBuf>,
+    /// Add the pulled crates and their snapshots to this SQLite database, updating any crates that
+    /// are already in it
+    #[cfg(feature = "sqlite")]
+    #[clap(long, value_name = "PATH")]
+    pub sqlite: Option<PathBuf>,
+    /// Only write one copy of byte-identical snapshots, recording the rest in `duplicates.jsonl`
+    #[clap(long)]
+    pub dedup: bool,
+    /// Stop at the first crate that fails to download or extract
+    #[clap(long, overrides_with = "keep-going")]
+    pub fail_on_error: bool,
+    /// Pull every crate that can be pulled even when some fail (the default)
+    #[clap(long, overrides_with = "fail-on-error")]
+    pub keep_going: bool,
+    /// How the download bar is drawn. `plain` avoids the block characters that some terminals
+    /// mangle
+    #[clap(long, arg_enum, value_name = "STYLE", default_value = "default")]
+    pub progress_style: ProgressStyleChoice,
+    /// A raw `indicatif` template used for the download bar instead of `--progress-style`
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        conflicts_with = "progress-style",
+        parse(try_from_str = parse_progress_template)
+    )]
+    pub progress_template: Option<String>,
+    /// Skip the crates recorded in this file by earlier runs, and record each crate here once it's
+    /// pulled
+    #[clap(long, value_name = "LEDGER")]
+    pub resume: Option<PathBuf>,
+    /// Print a JSON object to stdout for each crate as soon as it's pulled, failed, or found locally
+    #[clap(long)]
+    pub json_lines: bool,
+    /// List the crates in the out dir that don't use the deps anymore
+    #[clap(long)]
+    pub report_stale: bool,
+    /// Remove the crates in the out dir that don't use the deps anymore. Only lists them with
+    /// `--dry-run`
+    #[clap(long)]
+    pub prune_stale: bool,
+    /// Skip crates that didn't have any snapshots when they were pulled in an earlier run
+    #[clap(long)]
+    pub skip_snapshotless: bool,
+    /// Don't use a ledger even if one is set in the config file
+    #[clap(long, conflicts_with = "resume")]
+    pub no_resume: bool,
+    /// Only pull crates where the matching version was published on or after this date (e.g.
+    /// `2024-01-31` or `2024-01-31T12:00:00Z`). Needs `--with-crates-io-meta`
+    #[clap(
+        long,
+        value_name = "DATE",
+        requires = "with-crates-io-meta",
+        parse(try_from_str = parse_since)
+    )]
+    pub since: Option<SystemTime>,
+    /// Look up extra details that the index doesn't have through the crates.io API. This makes one
+    /// request per matching crate
+    #[clap(long)]
+    pub with_crates_io_meta: bool,
+    /// Name of a crate to skip. Can be passed multiple times
+    #[clap(long, value_name = "NAME")]
+    pub exclude: Vec<String>,
+    /// File listing crates to skip with one name per line. Blank lines and `#` comments are
+    /// ignored
+    #[clap(long, value_name = "PATH")]
+    pub exclude_file: Option<PathBuf>,
+}
+
+fn parse_jobs(s: &str) -> Result<usize, String> {
+    let jobs = s.parse().map_err(|e: ParseIntError| e.to_string())?;
+    check_jobs(jobs).map_err(ToOwned::to_owned)
+}
+
+// Also used on the config file's values, which don't go through clap
+pub fn check_jobs(jobs: usize) -> Result<usize, &'static str> {
+    match jobs {
+        0 => Err("there has to be at least one job"),
+        jobs => Ok(jobs),
+    }
+}
+
+pub fn parse_user_agent(s: &str) -> Result<String, &'static str> {
+    if s.trim().is_empty() {
+        Err("the user agent can't be empty")
+    } else {
+        Ok(s.to_owned())
+    }
+}
+
+// A plain number is in bytes
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num
+        .parse()
+        .map_err(|_| format!("{:?} doesn't start with a number", s))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        unit => {
+            return Err(format!(
+                "unknown unit {:?}. Use one of B, KB, MB, GB, KiB, MiB, or GiB",
+                unit
+            ))
+        }
+    };
+
+    Ok((num * multiplier as f64) as u64)
+}
+
+fn parse_registry_url(s: &str) -> Result<String, &'static str> {
+    if s.starts_with("https://") || s.starts_with("http://") {
+        Ok(s.trim_end_matches('/').to_owned())
+    } else {
+        Err("the registry url has to start with `https://` or `http://`")
+    }
+}
+
+fn parse_progress_template(s: &str) -> Result<String, String> {
+    dumpsta::validate_template(s)?;
+    Ok(s.to_owned())
+}
+
+fn parse_snapshot_glob(s: &str) -> Result<String, String> {
+    dumpsta::SnapshotGlobs::new(&[s]).map_err(|e| format!("{:#}", e))?;
+    Ok(s.to_owned())
+}
+
+// Plain dates are taken as the start of the day in UTC
+fn parse_since(s: &str) -> Result<SystemTime, humantime::TimestampError> {
+    if s.len() == "YYYY-MM-DD".len() {
+        humantime::parse_rfc3339(&format!("{}T00:00:00Z", s))
+    } else {
+        humantime::parse_rfc3339_weak(s)
+    }
+}
+
+fn parse_target(s: &str) -> Result<String, &'static str> {
+    match cfg_expr::targets::get_builtin_target_by_triple(s) {
+        Some(_) => Ok(s.to_owned()),
+        None => Err("unknown target triple"),
+    }
+}
+
+#[derive(clap::Args)]
+pub struct ListArgs {
+    #[clap(flatten)]
+    pub common: CommonArgs,
+    /// Also print each crate's requirements on the deps. They're always in the JSON output
+    #[clap(long)]
+    pub show_requirements: bool,
+}
+
+#[derive(clap::Args)]
+pub struct CrateArgs {
+    /// The crate to pull as `NAME` or `NAME@VERSION`. Uses the highest version when left out
+    #[clap(value_name = "NAME[@VERSION]")]
+    pub spec: CrateSpec,
+    #[clap(flatten)]
+    pub scan: ScanArgs,
+}
+
+#[derive(Clone, Debug)]
+pub struct CrateSpec {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl FromStr for CrateSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, version) = match s.split_once('@') {
+            Some((name, version)) => {
+                semver::Version::parse(version)
+                    .map_err(|e| format!("invalid version `{}`: {}", version, e))?;
+                (name, Some(version.to_owned()))
+            }
+            None => (s, None),
+        };
+        if name.is_empty() {
+            return Err("missing the crate name".to_owned());
+        }
+
+        Ok(Self {
+            name: name.to_owned(),
+            version,
+        })
+    }
+}
+
+// A thread count where `None` picks one per CPU
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Threads(pub Option<NonZeroUsize>);
+
+impl FromStr for Threads {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(|threads| Self(NonZeroUsize::new(threads)))
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn should_colorize(self) -> bool {
+        match self {
+            // All of our output goes to stderr, so that's the one that needs to be a TTY
+            Self::Auto => env::var_os("NO_COLOR").is_none() && io::stderr().is_terminal(),
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+pub enum ProgressStyleChoice {
+    Default,
+    Plain,
+    Bytes,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+pub enum SortChoice {
+    Downloads,
+    Name,
+    Version,
+    Recent,
+}
+
+impl SortChoice {
+    pub fn needs_crates_io_meta(self) -> bool {
+        matches!(self, Self::Downloads | Self::Recent)
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Pretty,
+    Json,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+pub enum YankPolicy {
+    Skip,
+    Include,
+    Only,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+pub enum VersionSelect {
+    Highest,
+    Lowest,
+    All,
+}
+
+impl From<VersionSelect> for dumpsta::VersionSelect {
+    fn from(select: VersionSelect) -> Self {
+        match select {
+            VersionSelect::Highest => Self::Highest,
+            VersionSelect::Lowest => Self::Lowest,
+            VersionSelect::All => Self::All,
+        }
+    }
+}
+
+impl From<YankPolicy> for dumpsta::YankPolicy {
+    fn from(policy: YankPolicy) -> Self {
+        match policy {
+            YankPolicy::Skip => Self::Skip,
+            YankPolicy::Include => Self::Include,
+            YankPolicy::Only => Self::Only,
+        }
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+pub enum DepKind {
+    Normal,
+    Dev,
+    Build,
+}
+
+impl From<DepKind> for DependencyKind {
+    fn from(kind: DepKind) -> Self {
+        match kind {
+            DepKind::Normal => Self::Normal,
+            DepKind::Dev => Self::Dev,
+            DepKind::Build => Self::Build,
+        }
+    }
 }
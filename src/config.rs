@@ -0,0 +1,180 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use clap::ArgMatches;
+use dumpsta::{dialog::Dialog, disps};
+use serde::Deserialize;
+
+use crate::cli::{self, CommonArgs, ScanArgs, Threads};
+
+const CONFIG_FILE: &str = "dumpsta.toml";
+
+// Defaults for the flags read from `dumpsta.toml`. Anything passed on the command line takes
+// precedence over these
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    dep: Option<Vec<String>>,
+    threads: Option<usize>,
+    all_versions: Option<bool>,
+    include_optional: Option<bool>,
+    cache_scan: Option<PathBuf>,
+    out_dir: Option<PathBuf>,
+    request_delay: Option<u64>,
+    retries: Option<usize>,
+    limit: Option<usize>,
+    user_agent: Option<String>,
+    download_jobs: Option<usize>,
+    registry: Option<String>,
+    crate_dir: Option<PathBuf>,
+    keep_crate: Option<bool>,
+    manifest: Option<PathBuf>,
+    dedup: Option<bool>,
+    resume: Option<PathBuf>,
+    // Collected so that typos can be pointed out instead of silently ignored
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+impl Config {
+    // An explicit `path` has to exist while the default locations are optional
+    pub fn load(path: Option<&PathBuf>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_owned(),
+            None => match default_path() {
+                Some(path) => path,
+                None => return Ok(Self::default()),
+            },
+        };
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed reading config file {}", path.display()))?;
+        let config = Self::parse(&contents, &path)?;
+
+        if !config.unknown.is_empty() {
+            let dialog = Dialog::raw_with_indent(NonZeroUsize::new(1).unwrap());
+            for key in config.unknown.keys() {
+                dialog.warn_with("Ignoring unknown key {} in {}", disps![key, path.as_path()])?;
+            }
+        }
+
+        Ok(config)
+    }
+
+    // Values get the same checks that their flags get from clap
+    fn parse(contents: &str, path: &Path) -> Result<Self> {
+        let config: Self = toml::from_str(contents)
+            .with_context(|| format!("Failed parsing config file {}", path.display()))?;
+        let invalid = |key: &str, e: &str| {
+            anyhow::anyhow!("Invalid `{}` in config file {}: {}", key, path.display(), e)
+        };
+        if let Some(user_agent) = &config.user_agent {
+            cli::parse_user_agent(user_agent).map_err(|e| invalid("user-agent", e))?;
+        }
+        if let Some(jobs) = config.download_jobs {
+            cli::check_jobs(jobs).map_err(|e| invalid("download-jobs", e))?;
+        }
+
+        Ok(config)
+    }
+
+    pub fn apply_common(&self, common: &mut CommonArgs, matches: &ArgMatches) {
+        let unset = |id| matches.occurrences_of(id) == 0;
+        merge(&mut common.deps, &self.dep, unset("deps"));
+        let threads_unset = unset("scan-threads") && unset("threads");
+        let threads = self
+            .threads
+            .map(|threads| Threads(NonZeroUsize::new(threads)));
+        merge(&mut common.scan_threads, &threads, threads_unset);
+        // An explicit `--version-select` wins over the older setting
+        merge(
+            &mut common.all_versions,
+            &self.all_versions,
+            unset("all-versions") && unset("version-select"),
+        );
+        merge(
+            &mut common.include_optional,
+            &self.include_optional,
+            unset("include-optional"),
+        );
+        merge_opt(
+            &mut common.cache_scan,
+            &self.cache_scan,
+            unset("cache-scan"),
+        );
+    }
+
+    pub fn apply_scan(&self, scan: &mut ScanArgs, matches: &ArgMatches) {
+        self.apply_common(&mut scan.common, matches);
+
+        let unset = |id| matches.occurrences_of(id) == 0;
+        merge_opt(&mut scan.out_dir, &self.out_dir, unset("out-dir"));
+        merge(
+            &mut scan.request_delay,
+            &self.request_delay,
+            unset("request-delay"),
+        );
+        merge(&mut scan.retries, &self.retries, unset("retries"));
+        merge_opt(&mut scan.limit, &self.limit, unset("limit"));
+        merge(&mut scan.user_agent, &self.user_agent, unset("user-agent"));
+        merge(
+            &mut scan.download_jobs,
+            &self.download_jobs,
+            unset("download-jobs"),
+        );
+        merge_opt(&mut scan.registry, &self.registry, unset("registry"));
+        merge_opt(&mut scan.crate_dir, &self.crate_dir, unset("crate-dir"));
+        let no_keep_crate = self.keep_crate.map(|keep_crate| !keep_crate);
+        let keep_crate_unset = unset("keep-crate") && unset("no-keep-crate");
+        merge(&mut scan.no_keep_crate, &no_keep_crate, keep_crate_unset);
+        merge_opt(&mut scan.manifest, &self.manifest, unset("manifest"));
+        merge(&mut scan.dedup, &self.dedup, unset("dedup"));
+        let resume_unset = unset("resume") && unset("no-resume");
+        merge_opt(&mut scan.resume, &self.resume, resume_unset);
+    }
+}
+
+fn merge<T: Clone>(arg: &mut T, val: &Option<T>, unset: bool) {
+    if let (Some(val), true) = (val, unset) {
+        *arg = val.to_owned();
+    }
+}
+
+fn merge_opt<T: Clone>(arg: &mut Option<T>, val: &Option<T>, unset: bool) {
+    if unset && val.is_some() {
+        *arg = val.to_owned();
+    }
+}
+
+// Checks the current dir before the user-wide config dir
+fn default_path() -> Option<PathBuf> {
+    let in_cwd = env::current_dir().ok().map(|dir| dir.join(CONFIG_FILE));
+    let user_wide = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(dirs::config_dir)
+        .map(|dir| dir.join("dumpsta").join(CONFIG_FILE));
+    [in_cwd, user_wide]
+        .into_iter()
+        .flatten()
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_like_the_flags() {
+        let path = Path::new(CONFIG_FILE);
+        let err = Config::parse("download-jobs = 0", path).unwrap_err();
+        assert!(err.to_string().contains("`download-jobs`"));
+        let err = Config::parse("user-agent = \" \"", path).unwrap_err();
+        assert!(err.to_string().contains("`user-agent`"));
+        let config = Config::parse("download-jobs = 2\nuser-agent = \"me\"", path).unwrap();
+        assert_eq!(config.download_jobs, Some(2));
+    }
+}
@@ -1,108 +1,291 @@
 use std::{
-    iter,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{self, Write},
     num::NonZeroUsize,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
 use colored::{Color, Colorize};
+use crates_index::Version;
+use indicatif::ProgressBar;
 use paste::paste;
 
+use crate::VersionExt;
+
 // Like `vec![]`, but the values can be heterogeneous as long as they can be used in `Disp::from`
+#[macro_export]
 macro_rules! disps {
     [$($disp:expr),* $(,)?] => {
         {
-            let mut v = Vec::new();
-            $(
-                v.push(crate::dialog::Disp::from($disp));
-            )*
-
+            let v: Vec<$crate::dialog::Disp> = vec![$($crate::dialog::Disp::from($disp)),*];
             v
         }
     }
 }
 
-pub(crate) use disps;
+pub use disps;
 
 enum DispType {
     Regular,
     Debug,
 }
 
-// TODO: should just store a reference
-pub enum Disp {
+enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+struct Padding {
+    fill: char,
+    align: Align,
+    width: usize,
+}
+
+impl Padding {
+    // Padding is worked out from the uncolored `s` since color escapes would throw off the width
+    fn apply(&self, s: &str, colored: &str) -> String {
+        let len = s.chars().count();
+        let missing = self.width.saturating_sub(len);
+        let (before, after) = match self.align {
+            Align::Left => (0, missing),
+            Align::Right => (missing, 0),
+            Align::Center => (missing / 2, missing - missing / 2),
+        };
+        let fill = |n| self.fill.to_string().repeat(n);
+        format!("{}{}{}", fill(before), colored, fill(after))
+    }
+}
+
+// Everything after the `:` in a marker
+struct Spec {
+    disp_type: DispType,
+    padding: Option<Padding>,
+    // Only used by floats
+    precision: Option<usize>,
+}
+
+impl Spec {
+    // Supports a subset of `format!()`'s `[[fill]align][width]['.' precision]['?']`
+    fn try_new(spec: &str) -> Option<Self> {
+        let (spec, disp_type) = match spec.strip_suffix('?') {
+            Some(rest) => (rest, DispType::Debug),
+            None => (spec, DispType::Regular),
+        };
+        // A `.` that isn't followed by just digits is left alone, since it can still be the fill
+        let (spec, precision) = match spec.rsplit_once('.') {
+            Some((rest, precision))
+                if !precision.is_empty() && precision.chars().all(|c| c.is_ascii_digit()) =>
+            {
+                (rest, Some(precision.parse().ok()?))
+            }
+            _ => (spec, None),
+        };
+
+        let to_align = |c| match c {
+            '<' => Some(Align::Left),
+            '>' => Some(Align::Right),
+            '^' => Some(Align::Center),
+            _ => None,
+        };
+        let mut chars = spec.chars();
+        let first = chars.next();
+        let second = chars.next();
+        let (fill, align, width) = match (first, second.and_then(to_align)) {
+            (Some(fill), Some(align)) => (fill, Some(align), &spec[fill.len_utf8() + 1..]),
+            _ => match first.and_then(to_align) {
+                Some(align) => (' ', Some(align), &spec[1..]),
+                None => (' ', None, spec),
+            },
+        };
+
+        let padding = if width.is_empty() {
+            None
+        } else if width.chars().all(|c| c.is_ascii_digit()) {
+            Some(Padding {
+                fill,
+                align: align.unwrap_or(Align::Left),
+                width: width.parse().ok()?,
+            })
+        } else {
+            return None;
+        };
+
+        Some(Self {
+            disp_type,
+            padding,
+            precision,
+        })
+    }
+}
+
+impl Default for Spec {
+    fn default() -> Self {
+        Self {
+            disp_type: DispType::Regular,
+            padding: None,
+            precision: None,
+        }
+    }
+}
+
+// Borrows where it can, so that displaying something doesn't have to clone it. Errors are still
+// owned since they're cheap to move and usually made on the spot
+pub enum Disp<'a> {
     Usize(usize),
-    Str(String),
-    Path(PathBuf),
+    Float(f64),
+    Str(Cow<'a, str>),
+    Path(Cow<'a, Path>),
     Error(anyhow::Error),
+    Duration(Duration),
+    // Displayed as `name version`
+    Version(&'a Version),
+    // A size in bytes. Made from `Bytes` to keep it apart from other numbers
+    Bytes(u64),
 }
 
-impl Disp {
-    fn fmt(&self, disp_type: &DispType, force_color: &Option<Color>) -> String {
-        let s = match disp_type {
+// A size in bytes that gets displayed like "1.4 GiB"
+#[derive(Clone, Copy, Debug)]
+pub struct Bytes(pub u64);
+
+// Renders like "512 B", "1.4 GiB", or "512 KiB"
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1_024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1_024.0;
+    let mut unit = UNITS[0];
+    for next_unit in &UNITS[1..] {
+        if size < 1_024.0 {
+            break;
+        }
+        size /= 1_024.0;
+        unit = next_unit;
+    }
+    let size = format!("{:.1}", size);
+    format!("{} {}", size.trim_end_matches(".0"), unit)
+}
+
+// Renders like "1.2s", "3m04s", or "1h02m03s"
+fn human_duration(duration: &Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else if secs < 60 * 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+    }
+}
+
+impl Disp<'_> {
+    // The text for each of the pieces that get displayed along with their usual color. Most things
+    // are a single piece
+    fn pieces(&self, disp_type: &DispType, precision: Option<usize>) -> Vec<(String, Color)> {
+        let (text, color) = match disp_type {
             DispType::Regular => match self {
-                Self::Usize(val) => val.to_string(),
-                Self::Str(val) => val.to_owned(),
-                Self::Path(val) => val.to_string_lossy().into_owned(),
-                Self::Error(val) => val.to_string(),
+                Self::Usize(val) => (val.to_string(), Color::Blue),
+                Self::Float(val) => (format!("{:.*}", precision.unwrap_or(1), val), Color::Blue),
+                Self::Str(val) => (val.to_string(), Color::Cyan),
+                Self::Path(val) => (val.to_string_lossy().into_owned(), Color::Cyan),
+                Self::Error(val) => (val.to_string(), Color::Red),
+                Self::Duration(val) => (human_duration(val), Color::Green),
+                Self::Bytes(val) => (human_bytes(*val), Color::Blue),
+                Self::Version(val) => {
+                    return vec![
+                        (val.name().to_owned(), Color::Cyan),
+                        (val.version().to_owned(), Color::Blue),
+                    ]
+                }
             },
             DispType::Debug => match self {
-                Self::Usize(val) => format!("{:?}", val),
-                Self::Str(val) => format!("{:?}", val),
-                Self::Path(val) => format!("{:?}", val),
-                Self::Error(val) => format!("{:?}", val),
+                Self::Usize(val) => (format!("{:?}", val), Color::Blue),
+                Self::Float(val) => (format!("{:?}", val), Color::Blue),
+                Self::Str(val) => (format!("{:?}", val), Color::Cyan),
+                Self::Path(val) => (format!("{:?}", val), Color::Cyan),
+                Self::Error(val) => (format!("{:?}", val), Color::Red),
+                Self::Duration(val) => (format!("{:?}", val), Color::Green),
+                Self::Bytes(val) => (format!("{:?}", val), Color::Blue),
+                Self::Version(val) => {
+                    return vec![
+                        (format!("{:?}", val.name()), Color::Cyan),
+                        (format!("{:?}", val.version()), Color::Blue),
+                    ]
+                }
             },
         };
 
-        let colored_str = match force_color {
-            Some(color) => s.color(*color),
-            None => match self {
-                Self::Usize(_) => s.blue(),
-                Self::Str(_) | Self::Path(_) => s.cyan(),
-                Self::Error(_) => s.red(),
-            },
-        };
+        vec![(text, color)]
+    }
 
-        format!("{}", colored_str)
+    fn fmt(&self, spec: &Spec, force_color: &Option<Color>) -> String {
+        let pieces = self.pieces(&spec.disp_type, spec.precision);
+        let s = pieces
+            .iter()
+            .map(|(text, _)| text.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let colored_str = pieces
+            .iter()
+            .map(|(text, color)| text.color(force_color.unwrap_or(*color)).to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        match &spec.padding {
+            Some(padding) => padding.apply(&s, &colored_str),
+            None => colored_str,
+        }
     }
 }
 
 macro_rules! disp_from {
     ($ty:ty, $variant:expr) => {
-        impl From<$ty> for Disp {
+        impl<'a> From<$ty> for Disp<'a> {
             fn from(val: $ty) -> Self {
-                $variant(val)
+                $variant(val.into())
             }
         }
     };
 }
 
 disp_from!(usize, Disp::Usize);
+disp_from!(f64, Disp::Float);
 disp_from!(String, Disp::Str);
+disp_from!(&'a str, Disp::Str);
+disp_from!(&'a String, Disp::Str);
 disp_from!(PathBuf, Disp::Path);
+disp_from!(&'a Path, Disp::Path);
+disp_from!(&'a PathBuf, Disp::Path);
 disp_from!(anyhow::Error, Disp::Error);
+disp_from!(Duration, Disp::Duration);
+disp_from!(&'a Version, Disp::Version);
 
-impl From<&str> for Disp {
-    fn from(s: &str) -> Self {
-        Self::Str(s.to_owned())
-    }
-}
-
-impl From<&String> for Disp {
-    fn from(s: &String) -> Self {
-        Self::Str(s.to_owned())
+impl From<Bytes> for Disp<'_> {
+    fn from(Bytes(bytes): Bytes) -> Self {
+        Self::Bytes(bytes)
     }
 }
 
-impl From<&Path> for Disp {
-    fn from(path: &Path) -> Self {
-        Self::Path(path.to_owned())
+impl<'a> From<&'a VersionExt> for Disp<'a> {
+    fn from(version: &'a VersionExt) -> Self {
+        Self::Version(version.inner())
     }
 }
 
 // TODO: take a &str
 enum Segment {
     Text(String),
-    Marker((DispType, Option<Color>)),
+    Marker((Spec, Option<Color>)),
+    Named((String, Spec, Option<Color>)),
 }
 
 impl Segment {
@@ -111,49 +294,130 @@ impl Segment {
     }
 }
 
-// TODO: this doesn't allow for having { or } in the string
-struct FmtStr {
+// Follows the same escaping rules as `format!()` where `{{` and `}}` are a literal `{` and `}`.
+// Messages that get displayed over and over can be parsed once up front and passed to the `*_fmt`
+// dialog methods
+pub struct FmtStr {
+    template: String,
     segments: Vec<Segment>,
 }
 
 impl FmtStr {
-    fn try_new(s: &str) -> Option<Self> {
+    pub fn parse(s: &str) -> Result<Self, DialogError> {
+        Self::try_new(s).map_err(|error| DialogError::InvalidTemplate {
+            template: s.to_owned(),
+            error,
+        })
+    }
+
+    fn try_new(s: &str) -> Result<Self, FmtStrError> {
         let mut segments = Vec::new();
-        let mut splits = s.split('{');
-
-        let first_text = splits.next()?;
-        segments.push(Segment::text(first_text));
-
-        // From this point on each split should start with whatever was within {} and then follow
-        // with some text
-        for split in splits {
-            let (within_marker, text) = split.split_once('}')?;
-            let disp_type = match within_marker {
-                "" => DispType::Regular,
-                ":?" => DispType::Debug,
-                _ => {
-                    return None;
+        let mut text = String::new();
+        let mut chars = s.char_indices().peekable();
+
+        while let Some((offset, c)) = chars.next() {
+            match c {
+                '{' if chars.next_if(|&(_, c)| c == '{').is_some() => text.push('{'),
+                '}' if chars.next_if(|&(_, c)| c == '}').is_some() => text.push('}'),
+                '{' => {
+                    let start = offset + 1;
+                    let end = loop {
+                        match chars.next() {
+                            Some((end, '}')) => break end,
+                            Some(_) => {}
+                            None => return Err(FmtStrError::new(offset, Reason::UnclosedMarker)),
+                        }
+                    };
+                    let within_marker = &s[start..end];
+
+                    let (name, spec) = match within_marker.split_once(':') {
+                        Some((name, spec)) => (name, Some(spec)),
+                        None => (within_marker, None),
+                    };
+                    let spec_start = start + name.len() + 1;
+                    let (spec, force_color) = match spec {
+                        None => (Spec::default(), None),
+                        Some(spec) => {
+                            let (spec, force_color) =
+                                split_color(spec).map_err(|color_offset| {
+                                    FmtStrError::new(
+                                        spec_start + color_offset,
+                                        Reason::UnknownColor,
+                                    )
+                                })?;
+                            let spec = Spec::try_new(spec)
+                                .ok_or_else(|| FmtStrError::new(spec_start, Reason::InvalidSpec))?;
+                            (spec, force_color)
+                        }
+                    };
+                    segments.push(Segment::text(&text));
+                    if name.is_empty() {
+                        segments.push(Segment::Marker((spec, force_color)));
+                    } else if is_identifier(name) {
+                        segments.push(Segment::Named((name.to_owned(), spec, force_color)));
+                    } else {
+                        return Err(FmtStrError::new(start, Reason::InvalidName));
+                    }
+                    text.clear();
                 }
-            };
-            segments.push(Segment::Marker((disp_type, None)));
-            segments.push(Segment::text(text));
+                // A lone `}` that isn't closing a marker
+                '}' => return Err(FmtStrError::new(offset, Reason::UnopenedMarker)),
+                c => text.push(c),
+            }
         }
+        segments.push(Segment::text(&text));
+
+        Ok(Self {
+            template: s.to_owned(),
+            segments,
+        })
+    }
 
-        Some(Self { segments })
+    fn num_markers(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|segment| matches!(segment, Segment::Marker(_)))
+            .count()
     }
 
-    fn try_fmt(&self, disps: &[Disp]) -> Option<String> {
+    fn has_named(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Named(_)))
+    }
+
+    fn format(&self, disps: &[Disp<'_>]) -> Result<String, DialogError> {
+        self.try_fmt(disps).ok_or_else(|| {
+            if self.has_named() {
+                DialogError::NamedMismatch(self.template.clone())
+            } else {
+                DialogError::DispCount {
+                    template: self.template.clone(),
+                    expected: self.num_markers(),
+                    found: disps.len(),
+                }
+            }
+        })
+    }
+
+    fn format_named(&self, disps: HashMap<&str, Disp<'_>>) -> Result<String, DialogError> {
+        self.try_fmt_named(disps)
+            .ok_or_else(|| DialogError::NamedMismatch(self.template.clone()))
+    }
+
+    fn try_fmt(&self, disps: &[Disp<'_>]) -> Option<String> {
         let segments = self.segments.iter();
         let mut disps = disps.iter();
 
         let mut s = String::new();
         for segment in segments {
             match segment {
-                Segment::Text(text) => s.push_str(&text),
-                Segment::Marker((disp_type, force_color)) => {
+                Segment::Text(text) => s.push_str(text),
+                Segment::Marker((spec, force_color)) => {
                     let disp = disps.next()?;
-                    s.push_str(&disp.fmt(disp_type, force_color));
+                    s.push_str(&disp.fmt(spec, force_color));
                 }
+                Segment::Named(_) => return None,
             }
         }
 
@@ -163,99 +427,671 @@ impl FmtStr {
 
         Some(s)
     }
+
+    // Like `try_fmt()`, but resolves `{name}` markers from `disps`. A name can be used multiple
+    // times, but every entry has to be used at least once
+    fn try_fmt_named(&self, disps: HashMap<&str, Disp<'_>>) -> Option<String> {
+        let mut used = HashSet::new();
+
+        let mut s = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => s.push_str(text),
+                Segment::Named((name, spec, force_color)) => {
+                    let disp = disps.get(name.as_str())?;
+                    s.push_str(&disp.fmt(spec, force_color));
+                    used.insert(name.as_str());
+                }
+                Segment::Marker(_) => return None,
+            }
+        }
+
+        if used.len() != disps.len() {
+            return None;
+        }
+
+        Some(s)
+    }
+}
+
+// Splits a trailing color name like `red` or `bright_blue` off of the end of a spec. Errors with
+// where the color starts if it isn't a known one
+fn split_color(spec: &str) -> Result<(&str, Option<Color>), usize> {
+    let rest = spec.trim_end_matches(|c: char| c.is_ascii_lowercase() || c == '_');
+    let name = &spec[rest.len()..];
+    if name.is_empty() {
+        Ok((spec, None))
+    } else {
+        let color = name.replace('_', " ").parse().map_err(|_| rest.len())?;
+        Ok((rest, Some(color)))
+    }
+}
+
+// Same rules as a rust identifier, minus the unicode
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Where parsing a template went wrong, as a byte offset into the template
+#[derive(Debug, PartialEq, Eq)]
+pub struct FmtStrError {
+    pub offset: usize,
+    pub reason: Reason,
+}
+
+impl FmtStrError {
+    fn new(offset: usize, reason: Reason) -> Self {
+        Self { offset, reason }
+    }
+}
+
+impl fmt::Display for FmtStrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.reason {
+            Reason::UnclosedMarker => "unclosed `{`",
+            Reason::UnopenedMarker => "unmatched `}`",
+            Reason::InvalidName => "invalid marker name",
+            Reason::UnknownColor => "unknown color",
+            Reason::InvalidSpec => "unsupported marker spec",
+        };
+        write!(f, "{} at byte {}", reason, self.offset)
+    }
+}
+
+impl std::error::Error for FmtStrError {}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reason {
+    // A `{` that never gets closed
+    UnclosedMarker,
+    // A `}` that isn't closing a marker. Literal braces are written as `{{` and `}}`
+    UnopenedMarker,
+    // A named marker's name isn't an identifier
+    InvalidName,
+    // The trailing color in a spec isn't one that `colored` knows
+    UnknownColor,
+    // What's left of the spec after the color isn't supported
+    InvalidSpec,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DialogError {
+    // The template itself couldn't be parsed
+    InvalidTemplate {
+        template: String,
+        error: FmtStrError,
+    },
+    // The number of disps didn't match the number of markers in the template
+    DispCount {
+        template: String,
+        expected: usize,
+        found: usize,
+    },
+    // The named markers in the template didn't line up with the provided disps
+    NamedMismatch(String),
+}
+
+impl fmt::Display for DialogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidTemplate { template, error } => {
+                write!(f, "Invalid dialog template {:?}: {}", template, error)
+            }
+            Self::DispCount {
+                template,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Dialog template {:?} expected {} values, but got {}",
+                template, expected, found
+            ),
+            Self::NamedMismatch(template) => write!(
+                f,
+                "Dialog template {:?} doesn't match the provided named values",
+                template
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DialogError {}
+
+fn try_format(msg: &str, disps: &[Disp<'_>]) -> Result<String, DialogError> {
+    FmtStr::parse(msg)?.format(disps)
+}
+
+fn try_format_named(msg: &str, disps: HashMap<&str, Disp<'_>>) -> Result<String, DialogError> {
+    FmtStr::parse(msg)?.format_named(disps)
+}
+
+// Formats the message without any of the dialog decorations. Useful for things like spinner
+// messages
+pub fn fmt_with<'a>(msg: &str, disps: impl AsRef<[Disp<'a>]>) -> Result<String, DialogError> {
+    try_format(msg, disps.as_ref())
+}
+
+// Like `fmt_with()`, but for templates with named markers
+pub fn fmt_named(msg: &str, disps: HashMap<&str, Disp<'_>>) -> Result<String, DialogError> {
+    try_format_named(msg, disps)
+}
+
+// The verbosity that new top-level dialogs pick up. Sub-dialogs inherit it from their parent
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+impl Verbosity {
+    pub fn global() -> Self {
+        match VERBOSITY.load(Ordering::Relaxed) {
+            0 => Self::Quiet,
+            1 => Self::Normal,
+            _ => Self::Verbose,
+        }
+    }
+
+    pub fn set_global(self) {
+        VERBOSITY.store(self as u8, Ordering::Relaxed);
+    }
 }
 
+// Generates the methods for a level where `$min_verbosity` is the lowest verbosity that the level
+// still gets printed at
 macro_rules! gen_dialog_level_methods {
-    ($level:ident, $color:expr) => {
+    ($level:ident, $color:expr, $min_verbosity:expr) => {
         paste! {
-            #[allow(dead_code)]
-            pub fn $level(&self, msg: &str) -> Self {
+            pub fn $level(&self, msg: &str) -> Result<Self, DialogError> {
                 self.[<$level _with>](msg, &[])
             }
 
-            #[allow(dead_code)]
-            pub fn [<$level _with>](&self, msg: &str, disps: impl AsRef<[Disp]>) -> Self {
-                self.msg_with($color, msg, disps)
+            pub fn [<$level _with>]<'a>(
+                &self,
+                msg: &str,
+                disps: impl AsRef<[Disp<'a>]>,
+            ) -> Result<Self, DialogError> {
+                self.msg_at_with($min_verbosity, $color, msg, disps)
+            }
+
+            pub fn [<$level _named>](
+                &self,
+                msg: &str,
+                disps: HashMap<&str, Disp<'_>>,
+            ) -> Result<Self, DialogError> {
+                self.msg_at_named($min_verbosity, $color, msg, disps)
+            }
+
+            // Like `*_with()`, but reuses an already parsed template
+            pub fn [<$level _fmt>]<'a>(
+                &self,
+                fmt_str: &FmtStr,
+                disps: impl AsRef<[Disp<'a>]>,
+            ) -> Result<Self, DialogError> {
+                let msg = fmt_str.format(disps.as_ref())?;
+                Ok(self.write_at($min_verbosity, $color, &msg))
             }
 
-            #[allow(dead_code)]
-            pub fn [<$level _str>](&self, msg: &str) -> (Self, String) {
+            pub fn [<$level _str>](&self, msg: &str) -> Result<(Self, String), DialogError> {
                 self.[<$level _str_with>](msg, &[])
             }
 
-            #[allow(dead_code)]
-            pub fn [<$level _str_with>](&self, msg: &str, disps: impl AsRef<[Disp]>) -> (Self, String) {
+            pub fn [<$level _str_with>]<'a>(
+                &self,
+                msg: &str,
+                disps: impl AsRef<[Disp<'a>]>,
+            ) -> Result<(Self, String), DialogError> {
                 self.msg_str_with($color, msg, disps)
             }
+
+            pub fn [<$level _str_fmt>]<'a>(
+                &self,
+                fmt_str: &FmtStr,
+                disps: impl AsRef<[Disp<'a>]>,
+            ) -> Result<(Self, String), DialogError> {
+                self.msg_str_fmt($color, fmt_str, disps)
+            }
         }
-    }
+    };
 }
 
-#[derive(Clone, Copy, Debug)]
+// Where a dialog's output is written to. Sub-dialogs share their parent's sink
+pub type Sink = Arc<Mutex<dyn Write + Send>>;
+
+pub fn stderr_sink() -> Sink {
+    Arc::new(Mutex::new(io::stderr()))
+}
+
+#[derive(Clone)]
 pub struct Dialog {
     indent: NonZeroUsize,
+    verbosity: Verbosity,
+    sink: Sink,
+    // Lines go above this bar while it's drawn, so that they don't mangle it
+    pb: Option<ProgressBar>,
+}
+
+impl fmt::Debug for Dialog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dialog")
+            .field("indent", &self.indent)
+            .field("verbosity", &self.verbosity)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Dialog {
     pub fn raw_with_indent(indent: NonZeroUsize) -> Self {
-        Self { indent }
+        Self::raw_with_sink(indent, stderr_sink())
+    }
+
+    pub fn raw_with_sink(indent: NonZeroUsize, sink: Sink) -> Self {
+        Self {
+            indent,
+            verbosity: Verbosity::global(),
+            sink,
+            pb: None,
+        }
     }
 
-    pub fn new(msg: &str) -> Self {
+    // Routes this dialog's output (and its sub-dialogs') through `pb` while it's being drawn
+    pub fn with_progress_bar(self, pb: ProgressBar) -> Self {
+        Self {
+            pb: Some(pb),
+            ..self
+        }
+    }
+
+    pub fn new(msg: &str) -> Result<Self, DialogError> {
         Self::new_with(msg, &[])
     }
 
-    pub fn new_with(msg: &str, disps: impl AsRef<[Disp]>) -> Self {
-        let fmt_str = FmtStr::try_new(msg).unwrap();
-        let disps = disps.as_ref();
+    pub fn new_with<'a>(msg: &str, disps: impl AsRef<[Disp<'a>]>) -> Result<Self, DialogError> {
+        Self::new_with_sink(stderr_sink(), msg, disps)
+    }
 
-        let msg = fmt_str.try_fmt(disps).unwrap();
-        eprintln!("{}", msg.bold());
+    pub fn new_with_sink<'a>(
+        sink: Sink,
+        msg: &str,
+        disps: impl AsRef<[Disp<'a>]>,
+    ) -> Result<Self, DialogError> {
+        Self::new_at_with(sink, Verbosity::Normal, msg, disps)
+    }
 
-        Self {
-            indent: NonZeroUsize::new(1).unwrap(),
+    // Like `new_with()`, but still gets displayed when running quietly
+    pub fn summary(msg: &str) -> Result<Self, DialogError> {
+        Self::summary_with(msg, &[])
+    }
+
+    pub fn summary_with<'a>(msg: &str, disps: impl AsRef<[Disp<'a>]>) -> Result<Self, DialogError> {
+        Self::new_at_with(stderr_sink(), Verbosity::Quiet, msg, disps)
+    }
+
+    fn new_at_with<'a>(
+        sink: Sink,
+        min_verbosity: Verbosity,
+        msg: &str,
+        disps: impl AsRef<[Disp<'a>]>,
+    ) -> Result<Self, DialogError> {
+        let dialog = Self::raw_with_sink(NonZeroUsize::new(1).unwrap(), sink);
+
+        let msg = try_format(msg, disps.as_ref())?;
+        if dialog.verbosity >= min_verbosity {
+            dialog.write_line(&msg.bold().to_string());
         }
+
+        Ok(dialog)
     }
 
-    gen_dialog_level_methods!(info, Color::Blue);
-    gen_dialog_level_methods!(warn, Color::Magenta);
-    gen_dialog_level_methods!(error, Color::Red);
+    // Output is best effort, so failing to write isn't treated as an error. A hidden bar doesn't
+    // print anything, so those lines go straight to the sink instead
+    fn write_line(&self, line: &str) {
+        if let Some(pb) = &self.pb {
+            if !pb.is_hidden() && !pb.is_finished() {
+                pb.println(line);
+                return;
+            }
+        }
 
-    #[allow(dead_code)]
-    pub fn msg(&self, color: Color, msg: &str) -> Self {
+        if let Ok(mut sink) = self.sink.lock() {
+            let _ = writeln!(sink, "{}", line);
+        }
+    }
+
+    gen_dialog_level_methods!(detail, Color::BrightBlack, Verbosity::Verbose);
+    gen_dialog_level_methods!(info, Color::Blue, Verbosity::Normal);
+    gen_dialog_level_methods!(warn, Color::Magenta, Verbosity::Quiet);
+    gen_dialog_level_methods!(error, Color::Red, Verbosity::Quiet);
+
+    pub fn msg(&self, color: Color, msg: &str) -> Result<Self, DialogError> {
         self.msg_with(color, msg, &[])
     }
 
-    #[allow(dead_code)]
-    pub fn msg_with(&self, color: Color, msg: &str, disps: impl AsRef<[Disp]>) -> Self {
-        let (sub_dialog, msg) = self.msg_str_with(color, msg, disps);
-        eprintln!("{msg}");
+    pub fn msg_with<'a>(
+        &self,
+        color: Color,
+        msg: &str,
+        disps: impl AsRef<[Disp<'a>]>,
+    ) -> Result<Self, DialogError> {
+        self.msg_at_with(Verbosity::Normal, color, msg, disps)
+    }
+
+    fn msg_at_with<'a>(
+        &self,
+        min_verbosity: Verbosity,
+        color: Color,
+        msg: &str,
+        disps: impl AsRef<[Disp<'a>]>,
+    ) -> Result<Self, DialogError> {
+        let msg = try_format(msg, disps.as_ref())?;
+        Ok(self.write_at(min_verbosity, color, &msg))
+    }
+
+    fn msg_at_named(
+        &self,
+        min_verbosity: Verbosity,
+        color: Color,
+        msg: &str,
+        disps: HashMap<&str, Disp<'_>>,
+    ) -> Result<Self, DialogError> {
+        let msg = try_format_named(msg, disps)?;
+        Ok(self.write_at(min_verbosity, color, &msg))
+    }
+
+    fn write_at(&self, min_verbosity: Verbosity, color: Color, msg: &str) -> Self {
+        let (sub_dialog, msg) = self.decorate(color, msg);
+        if self.verbosity >= min_verbosity {
+            self.write_line(&msg);
+        }
         sub_dialog
     }
 
-    #[allow(dead_code)]
-    pub fn msg_str(&self, color: Color, msg: &str) -> (Self, String) {
+    pub fn msg_fmt<'a>(
+        &self,
+        color: Color,
+        fmt_str: &FmtStr,
+        disps: impl AsRef<[Disp<'a>]>,
+    ) -> Result<Self, DialogError> {
+        let msg = fmt_str.format(disps.as_ref())?;
+        Ok(self.write_at(Verbosity::Normal, color, &msg))
+    }
+
+    pub fn msg_str(&self, color: Color, msg: &str) -> Result<(Self, String), DialogError> {
         self.msg_str_with(color, msg, &[])
     }
 
-    pub fn msg_str_with(
+    pub fn msg_str_with<'a>(
         &self,
         color: Color,
         msg: &str,
-        disps: impl AsRef<[Disp]>,
-    ) -> (Self, String) {
+        disps: impl AsRef<[Disp<'a>]>,
+    ) -> Result<(Self, String), DialogError> {
+        let msg = try_format(msg, disps.as_ref())?;
+        Ok(self.decorate(color, &msg))
+    }
+
+    pub fn msg_str_fmt<'a>(
+        &self,
+        color: Color,
+        fmt_str: &FmtStr,
+        disps: impl AsRef<[Disp<'a>]>,
+    ) -> Result<(Self, String), DialogError> {
+        let msg = fmt_str.format(disps.as_ref())?;
+        Ok(self.decorate(color, &msg))
+    }
+
+    // Adds the indent and arrow to an already formatted message. Any continuation lines get
+    // indented to line up with the first line's message instead of getting an arrow
+    fn decorate(&self, color: Color, msg: &str) -> (Self, String) {
         let arrow = "->".color(color).bold();
 
-        let indent_str: String = iter::repeat("  ").take(self.indent.get() - 1).collect();
+        let indent_str = "  ".repeat(self.indent.get() - 1);
 
-        let fmt_str = FmtStr::try_new(msg).unwrap();
-        let msg = fmt_str.try_fmt(disps.as_ref()).unwrap();
+        let continuation = format!("\n{}   ", indent_str);
+        let msg = msg.replace('\n', &continuation);
         let pretty_msg = format!("{}{} {}", indent_str, arrow, msg);
 
         let indent = self.indent.get().saturating_add(1);
         let sub_dialog = Self {
             indent: NonZeroUsize::new(indent).unwrap(),
+            verbosity: self.verbosity,
+            sink: Arc::clone(&self.sink),
+            pb: self.pb.clone(),
         };
         (sub_dialog, pretty_msg)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fmt(msg: &str, disps: &[Disp<'_>]) -> Option<String> {
+        colored::control::set_override(false);
+        FmtStr::try_new(msg).ok()?.try_fmt(disps)
+    }
+
+    #[test]
+    fn escaped_braces() {
+        assert_eq!(fmt("a {{ b }} c", &[]).unwrap(), "a { b } c");
+    }
+
+    #[test]
+    fn marker_adjacent_to_escapes() {
+        assert_eq!(fmt("{{{}}}", &disps!["val"]).unwrap(), "{val}");
+        // Same as `format!()` this is an unmatched `}` after the escaped `{`
+        assert!(fmt("{{}{}}", &disps!["val"]).is_none());
+    }
+
+    #[test]
+    fn durations() {
+        let secs = |secs| Disp::from(Duration::from_secs(secs));
+        assert_eq!(
+            fmt("{}", &[Disp::from(Duration::from_millis(1_234))]).unwrap(),
+            "1.2s"
+        );
+        assert_eq!(fmt("{}", &[secs(184)]).unwrap(), "3m04s");
+        assert_eq!(fmt("{}", &[secs(3_723)]).unwrap(), "1h02m03s");
+        assert_eq!(fmt("{:?}", &[secs(3)]).unwrap(), "3s");
+    }
+
+    #[test]
+    fn bytes() {
+        let bytes = |bytes| Disp::from(Bytes(bytes));
+        assert_eq!(fmt("{}", &[bytes(512)]).unwrap(), "512 B");
+        assert_eq!(fmt("{}", &[bytes(512 * 1_024)]).unwrap(), "512 KiB");
+        assert_eq!(fmt("{}", &[bytes(1_503_238_553)]).unwrap(), "1.4 GiB");
+        assert_eq!(fmt("{:>8}", &[bytes(1_536)]).unwrap(), " 1.5 KiB");
+        assert_eq!(fmt("{:?}", &[bytes(1_536)]).unwrap(), "1536");
+    }
+
+    #[test]
+    fn floats() {
+        let val = || Disp::from(98.246);
+        assert_eq!(fmt("{}%", &[val()]).unwrap(), "98.2%");
+        assert_eq!(fmt("{:.2}", &[val()]).unwrap(), "98.25");
+        assert_eq!(fmt("{:>7.0}", &[val()]).unwrap(), "     98");
+        assert_eq!(fmt("{:.<6}", &[Disp::from(1.0)]).unwrap(), "1.0...");
+        assert_eq!(fmt("{:?}", &[val()]).unwrap(), "98.246");
+    }
+
+    #[test]
+    fn versions() {
+        let version: Version = serde_json::from_str(
+            r#"{"name":"insta","vers":"1.2.3","deps":[],"cksum":"0000000000000000000000000000000000000000000000000000000000000000","features":{},"yanked":false}"#,
+        )
+        .unwrap();
+        assert_eq!(fmt("{}", &disps![&version]).unwrap(), "insta 1.2.3");
+        assert_eq!(
+            fmt("{:?}", &disps![&version]).unwrap(),
+            "\"insta\" \"1.2.3\""
+        );
+        assert_eq!(fmt("{:>12}|", &disps![&version]).unwrap(), " insta 1.2.3|");
+    }
+
+    #[test]
+    fn reused_templates() {
+        colored::control::set_override(false);
+        let fmt_str = FmtStr::parse("{} has {} snapshots").unwrap();
+        for num in 0..3 {
+            assert_eq!(
+                fmt_str.format(&disps!["insta", num]).unwrap(),
+                fmt_with("{} has {} snapshots", disps!["insta", num]).unwrap()
+            );
+        }
+        assert_eq!(
+            fmt_str.format(&disps!["insta"]),
+            Err(DialogError::DispCount {
+                template: "{} has {} snapshots".to_owned(),
+                expected: 2,
+                found: 1,
+            })
+        );
+        assert!(FmtStr::parse("{").is_err());
+    }
+
+    #[test]
+    fn mismatched_braces() {
+        assert!(fmt("{", &[]).is_none());
+        assert!(fmt("}", &[]).is_none());
+        assert!(fmt("a { b", &[]).is_none());
+        assert!(fmt("a } b", &[]).is_none());
+        assert!(fmt("{{}", &[]).is_none());
+    }
+
+    #[test]
+    fn error_offsets() {
+        let err = |msg| FmtStr::try_new(msg).err().map(|e| (e.offset, e.reason));
+        assert_eq!(err("{"), Some((0, Reason::UnclosedMarker)));
+        assert_eq!(err("ab {} {"), Some((6, Reason::UnclosedMarker)));
+        assert_eq!(err("a } b"), Some((2, Reason::UnopenedMarker)));
+        assert_eq!(err("a {1name}"), Some((3, Reason::InvalidName)));
+        assert_eq!(err("{:>8plaid}"), Some((4, Reason::UnknownColor)));
+        assert_eq!(err("{name:x?}"), Some((6, Reason::InvalidSpec)));
+        // Offsets are in bytes, not chars
+        assert_eq!(err("é {"), Some((3, Reason::UnclosedMarker)));
+        assert_eq!(err("{:>8}"), None);
+    }
+
+    fn silent_dialog() -> Dialog {
+        let sink: Sink = Arc::new(Mutex::new(io::sink()));
+        Dialog::raw_with_sink(NonZeroUsize::new(1).unwrap(), sink)
+    }
+
+    #[test]
+    fn mismatched_disp_count_is_err() {
+        let dialog = silent_dialog();
+        assert_eq!(
+            dialog.info_with("{} and {}", disps!["one"]).unwrap_err(),
+            DialogError::DispCount {
+                template: "{} and {}".to_owned(),
+                expected: 2,
+                found: 1,
+            }
+        );
+        assert!(dialog.warn_with("none", disps!["extra"]).is_err());
+        assert!(dialog.msg_str_with(Color::Green, "{}", &[]).is_err());
+        assert!(fmt_with("{} {}", disps![1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn multi_line_messages() {
+        colored::control::set_override(false);
+        let dialog = silent_dialog().info("Top").unwrap();
+        let (_, msg) = dialog
+            .error_str_with("Failed pulling\nCaused by: {}", disps!["timeout"])
+            .unwrap();
+        assert_eq!(msg, "  -> Failed pulling\n     Caused by: timeout");
+    }
+
+    #[test]
+    fn invalid_template_is_err() {
+        let sink: Sink = Arc::new(Mutex::new(io::sink()));
+        assert_eq!(
+            Dialog::new_with_sink(sink, "a } b", &[]).unwrap_err(),
+            DialogError::InvalidTemplate {
+                template: "a } b".to_owned(),
+                error: FmtStrError::new(2, Reason::UnopenedMarker),
+            }
+        );
+        assert!(silent_dialog().detail_with("{:x}", disps![1]).is_err());
+    }
+
+    fn fmt_named(msg: &str, disps: HashMap<&str, Disp<'_>>) -> Option<String> {
+        colored::control::set_override(false);
+        FmtStr::try_new(msg).ok()?.try_fmt_named(disps)
+    }
+
+    #[test]
+    fn named_markers() {
+        let disps = HashMap::from([("name", Disp::from("insta")), ("count", Disp::from(3))]);
+        assert_eq!(
+            fmt_named("{count} crates use {name:?}, {count}!", disps).unwrap(),
+            "3 crates use \"insta\", 3!"
+        );
+    }
+
+    #[test]
+    fn mismatched_named_markers() {
+        let disps = || HashMap::from([("name", Disp::from("insta"))]);
+        // Missing, unused, and positional markers are all rejected
+        assert!(fmt_named("{other}", disps()).is_none());
+        assert!(fmt_named("no markers", disps()).is_none());
+        assert!(fmt_named("{name} {}", disps()).is_none());
+        assert!(fmt("{name}", &disps!["insta"]).is_none());
+        assert!(fmt("{1name}", &[]).is_none());
+    }
+
+    #[test]
+    fn padding() {
+        assert_eq!(fmt("[{:>5}]", &disps![42]).unwrap(), "[   42]");
+        assert_eq!(fmt("[{:<5}]", &disps!["ab"]).unwrap(), "[ab   ]");
+        assert_eq!(fmt("[{:5}]", &disps!["ab"]).unwrap(), "[ab   ]");
+        assert_eq!(fmt("[{:*^6}]", &disps!["ab"]).unwrap(), "[**ab**]");
+        assert_eq!(fmt("[{:>6?}]", &disps!["ab"]).unwrap(), "[  \"ab\"]");
+        // Too long values are left alone
+        assert_eq!(fmt("[{:>2}]", &disps!["abcd"]).unwrap(), "[abcd]");
+        assert!(fmt("{:>x}", &disps!["ab"]).is_none());
+        assert!(fmt("{:?>}", &disps!["ab"]).is_some());
+    }
+
+    #[test]
+    fn padding_ignores_color_escapes() {
+        let padding = Padding {
+            fill: ' ',
+            align: Align::Right,
+            width: 4,
+        };
+        assert_eq!(
+            padding.apply("1", "\u{1b}[34m1\u{1b}[0m"),
+            "   \u{1b}[34m1\u{1b}[0m"
+        );
+    }
+
+    #[test]
+    fn inline_colors() {
+        let parse = |spec| {
+            split_color(spec)
+                .ok()
+                .map(|(rest, color)| (rest.to_owned(), color))
+        };
+        assert_eq!(parse("red"), Some((String::new(), Some(Color::Red))));
+        assert_eq!(parse("?green"), Some(("?".to_owned(), Some(Color::Green))));
+        assert_eq!(
+            parse(">8bright_blue"),
+            Some((">8".to_owned(), Some(Color::BrightBlue)))
+        );
+        assert_eq!(parse(">8?"), Some((">8?".to_owned(), None)));
+        assert_eq!(parse("plaid"), None);
+
+        assert_eq!(fmt("{:red} {:?green}", &disps![1, "a"]).unwrap(), "1 \"a\"");
+        assert!(fmt("{:plaid}", &disps![1]).is_none());
+    }
+}
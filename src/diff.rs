@@ -0,0 +1,215 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{dialog::Dialog, disps, VersionExt};
+
+// The crates matched by a run, keyed by name so that version bumps can be told apart from crates
+// coming and going
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MatchSet(BTreeMap<String, BTreeSet<String>>);
+
+// Covers the scan cache's `Version`s along with the JSON records and manifest lines
+#[derive(Deserialize)]
+struct PrevMatch {
+    name: String,
+    #[serde(alias = "vers")]
+    version: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PrevRun {
+    ScanCache { matches: Vec<PrevMatch> },
+    Records(Vec<PrevMatch>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PrevLine {
+    Records(Vec<PrevMatch>),
+    Record(PrevMatch),
+    // The JSON output also has the summary as a later line
+    Other {},
+}
+
+impl MatchSet {
+    pub fn from_versions(versions: &[VersionExt]) -> Self {
+        Self::from_pairs(
+            versions
+                .iter()
+                .map(|version| (version.name().to_owned(), version.version().to_owned())),
+        )
+    }
+
+    // Loads a previous scan cache, `--format json` output, or `--manifest`
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed reading previous run {}", path.display()))?;
+        Self::parse(&contents)
+            .with_context(|| format!("Failed parsing previous run {}", path.display()))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let matches = match serde_json::from_str(contents) {
+            Ok(PrevRun::ScanCache { matches }) | Ok(PrevRun::Records(matches)) => matches,
+            // Otherwise it should be JSON lines like the manifest
+            Err(_) => {
+                let mut matches = Vec::new();
+                for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+                    match serde_json::from_str(line)? {
+                        PrevLine::Records(records) => matches.extend(records),
+                        PrevLine::Record(record) => matches.push(record),
+                        PrevLine::Other {} => {}
+                    }
+                }
+                matches
+            }
+        };
+
+        Ok(Self::from_pairs(
+            matches.into_iter().map(|m| (m.name, m.version)),
+        ))
+    }
+
+    fn from_pairs(pairs: impl Iterator<Item = (String, String)>) -> Self {
+        let mut set = Self::default();
+        for (name, version) in pairs {
+            set.0.entry(name).or_default().insert(version);
+        }
+        set
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct CrateVersions {
+    name: String,
+    versions: Vec<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ChangedCrate {
+    name: String,
+    from: Vec<String>,
+    to: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct MatchDiff {
+    added: Vec<CrateVersions>,
+    // Crates that dropped the dep, got yanked, or were filtered out this time
+    removed: Vec<CrateVersions>,
+    changed: Vec<ChangedCrate>,
+}
+
+impl MatchDiff {
+    pub fn new(prev: &MatchSet, current: &MatchSet) -> Self {
+        let mut diff = Self::default();
+        for (name, versions) in &current.0 {
+            match prev.0.get(name) {
+                None => diff.added.push(CrateVersions {
+                    name: name.to_owned(),
+                    versions: versions.iter().cloned().collect(),
+                }),
+                Some(prev_versions) if prev_versions != versions => {
+                    diff.changed.push(ChangedCrate {
+                        name: name.to_owned(),
+                        from: prev_versions.iter().cloned().collect(),
+                        to: versions.iter().cloned().collect(),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+        for (name, versions) in &prev.0 {
+            if !current.0.contains_key(name) {
+                diff.removed.push(CrateVersions {
+                    name: name.to_owned(),
+                    versions: versions.iter().cloned().collect(),
+                });
+            }
+        }
+
+        diff
+    }
+
+    pub fn report(&self) -> Result<()> {
+        let dialog = Dialog::new_with(
+            "Compared to the previous run {} crates are new, {} are gone, and {} changed versions",
+            disps![self.added.len(), self.removed.len(), self.changed.len()],
+        )?;
+        for added in &self.added {
+            dialog.info_with("+ {} {}", disps![&added.name, added.versions.join(", ")])?;
+        }
+        for removed in &self.removed {
+            dialog.info_with(
+                "- {} {}",
+                disps![&removed.name, removed.versions.join(", ")],
+            )?;
+        }
+        for changed in &self.changed {
+            dialog.info_with(
+                "~ {} {} -> {}",
+                disps![
+                    &changed.name,
+                    changed.from.join(", "),
+                    changed.to.join(", ")
+                ],
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_previous_runs() {
+        let scan_cache = r#"{"timestamp":0,"options":"","matches":[
+            {"name":"foo","vers":"1.0.0","deps":[],"features":{},"cksum":"","yanked":false},
+            {"name":"bar","vers":"0.1.0","deps":[],"features":{},"cksum":"","yanked":false}
+        ]}"#;
+        let manifest = "{\"name\":\"foo\",\"version\":\"1.0.0\",\"path\":\"a.snap\",\"size\":1}\n\
+                        {\"name\":\"bar\",\"version\":\"0.1.0\",\"path\":\"b.snap\",\"size\":1}\n";
+        let json_output = "[{\"name\":\"foo\",\"version\":\"1.0.0\",\"download_url\":null},\
+                           {\"name\":\"bar\",\"version\":\"0.1.0\",\"download_url\":null}]\n\
+                           {\"scanned\":2,\"matched\":2}\n";
+        let prev = MatchSet::parse(scan_cache).unwrap();
+        assert_eq!(prev, MatchSet::parse(manifest).unwrap());
+        assert_eq!(prev, MatchSet::parse(json_output).unwrap());
+
+        let current = MatchSet::from_pairs(
+            [("foo", "1.1.0"), ("baz", "2.0.0")]
+                .into_iter()
+                .map(|(name, version)| (name.to_owned(), version.to_owned())),
+        );
+        let diff = MatchDiff::new(&prev, &current);
+        let owned = |versions: &[&str]| versions.iter().map(|v| v.to_string()).collect();
+        assert_eq!(
+            diff,
+            MatchDiff {
+                added: vec![CrateVersions {
+                    name: "baz".to_owned(),
+                    versions: owned(&["2.0.0"]),
+                }],
+                removed: vec![CrateVersions {
+                    name: "bar".to_owned(),
+                    versions: owned(&["0.1.0"]),
+                }],
+                changed: vec![ChangedCrate {
+                    name: "foo".to_owned(),
+                    from: owned(&["1.0.0"]),
+                    to: owned(&["1.1.0"]),
+                }],
+            }
+        );
+    }
+}
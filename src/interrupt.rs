@@ -0,0 +1,80 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    process,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use anyhow::Result;
+
+use crate::{dialog::Dialog, disps};
+
+// Paths that are partway through being written. These get removed when interrupted, so that the
+// next run doesn't mistake them for being complete
+static IN_PROGRESS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+// What shells report for a process killed by SIGINT
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+fn in_progress() -> MutexGuard<'static, BTreeSet<PathBuf>> {
+    IN_PROGRESS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub fn install_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        // The lock is held until exiting, so nothing can be completed in the meantime
+        let mut in_progress = in_progress();
+        let mut removed = Vec::new();
+        for path in in_progress.iter() {
+            let result = if path.is_dir() {
+                fs::remove_dir_all(path)
+            } else {
+                fs::remove_file(path)
+            };
+            if result.is_ok() {
+                removed.push(path.to_owned());
+            }
+        }
+        in_progress.clear();
+
+        let dialog = Dialog::raw_with_indent(NonZeroUsize::new(1).unwrap());
+        // We're exiting either way, so there's nothing to do if reporting fails
+        if let Ok(sub_dialog) = dialog.warn_with(
+            "Interrupted! Cleaned up {} partially written files",
+            disps![removed.len()],
+        ) {
+            for path in removed {
+                let _ = sub_dialog.warn_with("Removed {}", disps![path]);
+            }
+        }
+        process::exit(INTERRUPTED_EXIT_CODE);
+    })?;
+
+    Ok(())
+}
+
+// Marks `path` as in progress until it's completed or this is dropped
+pub struct InProgress(PathBuf);
+
+impl InProgress {
+    pub fn new(path: PathBuf) -> Self {
+        in_progress().insert(path.clone());
+        Self(path)
+    }
+}
+
+impl Drop for InProgress {
+    fn drop(&mut self) {
+        in_progress().remove(&self.0);
+    }
+}
+
+// Runs the final step of writing `path` without letting an interrupt clean it up partway through
+pub fn complete<T>(path: &Path, finish: impl FnOnce() -> T) -> T {
+    let mut in_progress = in_progress();
+    let res = finish();
+    in_progress.remove(path);
+    res
+}
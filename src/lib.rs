@@ -0,0 +1,3581 @@
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env,
+    ffi::OsString,
+    fmt,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    num::NonZeroUsize,
+    ops::Deref,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{self, AtomicBool, AtomicUsize},
+        mpsc, Mutex,
+    },
+    thread::{self, sleep},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use cfg_expr::{targets, Expression, Predicate};
+use colored::{Color, Colorize};
+use crates_index::{Dependency, DependencyKind, Index, IndexConfig, Version};
+use flate2::bufread::GzDecoder;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use indicatif::{HumanBytes, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rayon::{iter::Either, prelude::*, ThreadPoolBuilder};
+use regex::{bytes::Regex as BytesRegex, Regex};
+use semver::VersionReq;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tar::{Archive, Entry};
+
+pub mod dialog;
+pub mod diff;
+pub mod interrupt;
+mod req;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod state;
+
+use dialog::{Bytes, Dialog, Disp, FmtStr, Verbosity};
+use interrupt::InProgress;
+use state::{Ledger, ListedSnapshot, Listing, Listings, ScanRecord, State, TreeStamp};
+use ureq::Agent;
+
+#[derive(Debug, Clone)]
+pub struct CargoRegistry {
+    base: PathBuf,
+    index_name: OsString,
+}
+
+// The directory names for sparse registries all start with this, e.g. `index.crates.io-<hash>`
+const SPARSE_INDEX_PREFIX: &str = "index.crates.io-";
+
+// Points directly at the registry dir for setups that moved it away from `$CARGO_HOME/registry`
+const REGISTRY_ENV_VAR: &str = "DUMPSTA_REGISTRY";
+
+impl CargoRegistry {
+    // Cargo's config has no way to relocate the registry on its own, so past our override it's
+    // always under `$CARGO_HOME`
+    fn base() -> Result<PathBuf> {
+        if let Some(registry) = env::var_os(REGISTRY_ENV_VAR).filter(|dir| !dir.is_empty()) {
+            return Ok(PathBuf::from(registry));
+        }
+
+        let cargo_home = match env::var_os("CARGO_HOME") {
+            Some(home) => PathBuf::from(home),
+            None => {
+                let home_dir = dirs::home_dir().context("Failed to get home dir")?;
+                home_dir.join(".cargo")
+            }
+        };
+        Ok(cargo_home.join("registry"))
+    }
+
+    // Uses the registry named `name` when provided. Otherwise the sparse index is preferred when
+    // there are multiple to pick from
+    pub fn new(name: Option<&str>) -> Result<Self> {
+        let base = Self::base()?;
+
+        let cache_dir = base.join("cache");
+        let mut index_names: Vec<_> = cache_dir
+            .read_dir()
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .map(|entry| entry.file_name())
+                    .collect()
+            })
+            .unwrap_or_default();
+        index_names.sort();
+
+        let dialog = Dialog::raw_with_indent(NonZeroUsize::new(1).unwrap());
+        if index_names.is_empty() {
+            let (problem, path) = if base.is_dir() {
+                ("The registry cache at {} is empty", cache_dir)
+            } else {
+                ("There's no cargo registry at {}", base)
+            };
+            dialog
+                .error_with(problem, disps![path])?
+                .error("Running `cargo fetch` in a project (or building one) will populate it")?;
+            anyhow::bail!("Couldn't find a cargo registry");
+        }
+
+        let index_name = match name {
+            Some(name) => match index_names.iter().find(|index_name| *index_name == name) {
+                Some(index_name) => index_name.to_owned(),
+                None => {
+                    let available = index_names
+                        .iter()
+                        .map(|name| name.to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    dialog.error_with(
+                        "No registry named {} in {}. Available: {}",
+                        disps![name, cache_dir, available],
+                    )?;
+                    anyhow::bail!("Unknown registry {}", name);
+                }
+            },
+            None => index_names
+                .iter()
+                .find(|index_name| {
+                    index_name
+                        .to_str()
+                        .is_some_and(|name| name.starts_with(SPARSE_INDEX_PREFIX))
+                })
+                .unwrap_or(&index_names[0])
+                .to_owned(),
+        };
+
+        tracing::debug!(base = %base.display(), ?index_name, "picked the registry");
+        Ok(CargoRegistry { base, index_name })
+    }
+
+    pub fn cache(&self) -> PathBuf {
+        self.sub_dir("cache")
+    }
+
+    pub fn index(&self) -> PathBuf {
+        self.sub_dir("index")
+    }
+
+    pub fn src(&self) -> PathBuf {
+        self.sub_dir("src")
+    }
+
+    // Whether `path` is somewhere within the registry
+    pub fn contains(&self, path: &Path) -> bool {
+        let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        let path = env::current_dir()
+            .map(|cwd| cwd.join(path))
+            .unwrap_or_else(|_| path.to_owned());
+        // The path may not exist yet, so its closest existing ancestor stands in for it
+        let existing = path.ancestors().find(|path| path.exists());
+        existing.is_some_and(|path| canonical(path).starts_with(canonical(&self.base)))
+    }
+
+    fn sub_dir(&self, dir: &'static str) -> PathBuf {
+        self.base.join(dir).join(&self.index_name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalCrates {
+    // Every dir name along with where it was found, in the order of the srcs
+    listing: BTreeMap<String, Vec<PathBuf>>,
+    // The dir names of the listing that parse, keyed by their name and version
+    crates: HashMap<(String, semver::Version), String>,
+}
+
+impl LocalCrates {
+    // Searches each of the `srcs` for extracted crates. Ones that don't exist yet are skipped
+    pub fn new(srcs: &[PathBuf]) -> Result<Self> {
+        let mut listing: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for src in srcs {
+            let entries = match src.read_dir() {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed reading {}", src.display()))
+                }
+            };
+            // Read over entries ignoring any errors
+            for entry in entries.filter_map(Result::ok) {
+                if let Some(dir_name) = entry.file_name().to_str() {
+                    listing
+                        .entry(dir_name.to_owned())
+                        .or_default()
+                        .push(entry.path());
+                }
+            }
+        }
+        let crates = listing
+            .keys()
+            .filter_map(|dir_name| {
+                let (name, version) = parse_dir_name(dir_name)?;
+                Some(((name.to_owned(), version), dir_name.to_owned()))
+            })
+            .collect();
+        Ok(Self { listing, crates })
+    }
+
+    // The `name-version` and path of each extracted crate
+    pub fn dirs(&self) -> impl Iterator<Item = (&str, PathBuf)> {
+        self.listing.iter().flat_map(|(name, paths)| {
+            paths
+                .iter()
+                .map(move |path| (name.as_str(), path.to_owned()))
+        })
+    }
+
+    // Only counts crates that finished extracting, so that interrupted runs get picked back up
+    pub fn contains(&self, version: &VersionExt) -> bool {
+        self.dir_of(version).is_some()
+    }
+
+    // Where the crate got fully extracted to
+    pub fn dir_of(&self, version: &VersionExt) -> Option<PathBuf> {
+        let dir_name = match semver::Version::parse(version.version()) {
+            Ok(semver) => self
+                .crates
+                .get(&(version.name().to_owned(), semver))
+                .cloned(),
+            // Odd versions can still match on the exact dir name
+            Err(_) => Some(version.dir_name()),
+        };
+        self.listing
+            .get(&dir_name?)?
+            .iter()
+            .find(|dir| is_fully_extracted(dir))
+            .cloned()
+    }
+}
+
+// Splits a `name-version` dir name back into its parts. Crate names can contain `-`s (and digits),
+// but never `.`s, so the version starts at the first `-` where the rest is a valid semver version.
+// Taking the first one is what keeps a pre-release like `1.0.0-b-2.0.0` whole, since every later
+// `-` is already inside the version and the name can't have a `.` to begin another one
+pub fn parse_dir_name(dir_name: &str) -> Option<(&str, semver::Version)> {
+    dir_name
+        .match_indices('-')
+        .map(|(i, _)| (&dir_name[..i], &dir_name[i + 1..]))
+        .filter(|(name, _)| !name.is_empty())
+        .find_map(|(name, version)| {
+            let version = semver::Version::parse(version).ok()?;
+            // Rejects anything that semver would normalize into something else
+            (format!("{}-{}", name, version) == dir_name).then_some((name, version))
+        })
+}
+
+// Cargo writes `.cargo-ok` once it's done extracting a crate and we do the same with our own file.
+// Ours can't reuse cargo's since we only extract the snapshots
+const CARGO_OK_FILE: &str = ".cargo-ok";
+const EXTRACTED_OK_FILE: &str = ".dumpsta-ok";
+
+fn is_fully_extracted(dir: &Path) -> bool {
+    [CARGO_OK_FILE, EXTRACTED_OK_FILE]
+        .iter()
+        .any(|ok_file| dir.join(ok_file).is_file())
+}
+
+#[derive(Debug, Clone)]
+pub struct VersionExt(Version);
+
+impl VersionExt {
+    pub fn new(version: Version) -> Self {
+        Self(version)
+    }
+
+    pub fn inner(&self) -> &Version {
+        &self.0
+    }
+
+    // The `name-version` that cargo uses for the crate's directory
+    pub fn dir_name(&self) -> String {
+        format!("{}-{}", self.name(), self.version())
+    }
+}
+
+impl From<Version> for VersionExt {
+    fn from(version: Version) -> Self {
+        Self::new(version)
+    }
+}
+
+impl Deref for VersionExt {
+    type Target = Version;
+
+    fn deref(&self) -> &Self::Target {
+        self.inner()
+    }
+}
+
+impl PartialEq for VersionExt {
+    fn eq(&self, other: &Self) -> bool {
+        self.name() == other.name() && self.version() == other.version()
+    }
+}
+
+impl Eq for VersionExt {}
+
+impl Hash for VersionExt {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name().hash(state);
+        self.version().hash(state);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DepFilter {
+    pub names: Vec<String>,
+    pub req: Option<VersionReq>,
+    pub kinds: Vec<DependencyKind>,
+    pub include_optional: bool,
+    // Only deps that are gated to this target triple get matched when set
+    pub only_target: Option<String>,
+    // Only crates with names matching this get considered at all when set
+    pub crate_filter: Option<Regex>,
+    pub yank_policy: YankPolicy,
+    // Pre-release versions are only picked, and only count as matching `req`, when set
+    pub include_pre: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum YankPolicy {
+    // Falls back to the highest version that wasn't yanked
+    #[default]
+    Skip,
+    Include,
+    Only,
+}
+
+impl YankPolicy {
+    fn allows(self, version: &Version) -> bool {
+        match self {
+            Self::Skip => !version.is_yanked(),
+            Self::Include => true,
+            Self::Only => version.is_yanked(),
+        }
+    }
+}
+
+// Which of a crate's versions get matched against the deps
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum VersionSelect {
+    // The crate's current state, so a crate that dropped the dep doesn't match
+    #[default]
+    Highest,
+    // The earliest version that used the dep, which is when the crate picked it up
+    Lowest,
+    All,
+}
+
+impl DepFilter {
+    // Matches `dep` used as any kind of dependency with any requirement
+    pub fn any_kind(dep: &str) -> Self {
+        Self {
+            names: vec![dep.to_owned()],
+            req: None,
+            kinds: vec![
+                DependencyKind::Normal,
+                DependencyKind::Dev,
+                DependencyKind::Build,
+            ],
+            include_optional: true,
+            only_target: None,
+            crate_filter: None,
+            yank_policy: YankPolicy::default(),
+            include_pre: false,
+        }
+    }
+
+    fn allows(&self, version: &Version) -> bool {
+        let is_pre = || {
+            semver::Version::parse(version.version()).is_ok_and(|version| !version.pre.is_empty())
+        };
+        self.yank_policy.allows(version) && (self.include_pre || !is_pre())
+    }
+
+    // Picks out which of a crate's `versions` get checked, lowest first. A crate with no allowed
+    // versions is left out entirely
+    fn select_versions(&self, versions: &[Version], select: VersionSelect) -> Vec<Version> {
+        let semver = |version: &Version| semver::Version::parse(version.version()).ok();
+        let allowed = versions.iter().filter(|version| self.allows(version));
+        match select {
+            // Same as `Crate::highest_version()`, but only out of the allowed versions
+            VersionSelect::Highest => allowed
+                .max_by_key(|version| semver(version))
+                .into_iter()
+                .cloned()
+                .collect(),
+            VersionSelect::Lowest | VersionSelect::All => {
+                let mut allowed: Vec<_> = allowed.cloned().collect();
+                allowed.sort_by_key(semver);
+                allowed
+            }
+        }
+    }
+
+    // The selected versions of a crate that use any of the deps. With `Lowest` that's only the
+    // first version to match, so every version has to be looked at to find it
+    fn matching_versions(
+        &self,
+        versions: &[Version],
+        select: VersionSelect,
+    ) -> Vec<Result<VersionExt, SkippedVersion>> {
+        let mut found = Vec::new();
+        for version in self.select_versions(versions, select) {
+            let version = VersionExt::from(version);
+            match self.uses_any(&version) {
+                Ok(true) => {
+                    found.push(Ok(version));
+                    if select == VersionSelect::Lowest {
+                        break;
+                    }
+                }
+                Ok(false) => {}
+                Err(requirement) => found.push(Err(SkippedVersion {
+                    version,
+                    requirement,
+                })),
+            }
+        }
+
+        found
+    }
+
+    pub fn is_scanned(&self, crate_name: &str) -> bool {
+        self.crate_filter
+            .as_ref()
+            .is_none_or(|filter| filter.is_match(crate_name))
+    }
+
+    fn is_considered(&self, dep: &Dependency) -> bool {
+        self.kinds.contains(&dep.kind())
+            && (self.include_optional || !dep.is_optional())
+            && self.matches_target(dep)
+    }
+
+    fn matches_target(&self, dep: &Dependency) -> bool {
+        let triple = match &self.only_target {
+            Some(triple) => triple,
+            None => return true,
+        };
+
+        match dep.target() {
+            None => false,
+            Some(target) if target.starts_with("cfg(") => {
+                let expr = Expression::parse(target);
+                let info = targets::get_builtin_target_by_triple(triple);
+                match (expr, info) {
+                    (Ok(expr), Some(info)) => expr.eval(|pred| match pred {
+                        Predicate::Target(pred) => pred.matches(info),
+                        _ => false,
+                    }),
+                    _ => false,
+                }
+            }
+            Some(target) => target == triple,
+        }
+    }
+
+    // Whether `version` has a dependency of a selected kind that `is_match`es and can overlap with
+    // our requirement. A dependency requirement that fails to parse gets returned as the error
+    pub fn uses_matching(
+        &self,
+        version: &VersionExt,
+        is_match: impl Fn(&Dependency) -> bool,
+    ) -> Result<bool, String> {
+        let deps = version.dependencies().iter();
+        for dep in deps.filter(|dep| self.is_considered(dep) && is_match(dep)) {
+            let req = match &self.req {
+                Some(req) => req,
+                None => return Ok(true),
+            };
+
+            let dep_req =
+                VersionReq::parse(dep.requirement()).map_err(|_| dep.requirement().to_owned())?;
+            if req::overlaps(req, &dep_req, self.include_pre) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    // Compares against the actual package rather than the (possibly renamed) local name, so
+    // `snap = { package = "insta" }` matches `insta` while `insta = { package = "other" }` doesn't
+    fn is_target(&self, dep: &Dependency) -> bool {
+        self.names.iter().any(|name| name == dep.crate_name())
+    }
+
+    pub fn uses(&self, version: &VersionExt, name: &str) -> Result<bool, String> {
+        self.uses_matching(version, |dep| dep.crate_name() == name)
+    }
+
+    pub fn uses_any(&self, version: &VersionExt) -> Result<bool, String> {
+        self.uses_matching(version, |dep| self.is_target(dep))
+    }
+
+    pub fn uses_via(&self, version: &VersionExt, kind: DependencyKind) -> Result<bool, String> {
+        self.uses_matching(version, |dep| dep.kind() == kind && self.is_target(dep))
+    }
+
+    pub fn uses_only_optionally(&self, version: &VersionExt) -> Result<bool, String> {
+        let uses_required =
+            self.uses_matching(version, |dep| !dep.is_optional() && self.is_target(dep))?;
+        Ok(!uses_required && self.uses_any(version)?)
+    }
+
+    // Every dependency of `version` that's on one of the deps and of a selected kind
+    pub fn matching_deps<'a>(
+        &'a self,
+        version: &'a VersionExt,
+    ) -> impl Iterator<Item = &'a Dependency> + 'a {
+        version
+            .dependencies()
+            .iter()
+            .filter(|dep| self.is_considered(dep) && self.is_target(dep))
+    }
+}
+
+const NUM_TOP_REQUIREMENTS: usize = 10;
+
+// Aggregate statistics on how the matching crates depend on the deps
+#[derive(Serialize)]
+pub struct DepStats {
+    pub total: usize,
+    // A crate can use the deps via several kinds, so these can add up to more than the total
+    pub by_kind: BTreeMap<&'static str, usize>,
+    // How tightly the requirements constrain the version e.g. `pinned` for `=1.2.3`
+    pub requirement_kinds: BTreeMap<&'static str, usize>,
+    // Most common first
+    pub top_requirements: Vec<RequirementCount>,
+}
+
+#[derive(Serialize)]
+pub struct RequirementCount {
+    pub requirement: String,
+    pub count: usize,
+}
+
+impl DepStats {
+    pub fn new(filter: &DepFilter, dependents: &[VersionExt]) -> Self {
+        let mut by_kind = BTreeMap::new();
+        let mut requirement_kinds = BTreeMap::new();
+        let mut requirements: HashMap<&str, usize> = HashMap::new();
+        for version in dependents {
+            // Each crate only gets counted once per kind and requirement
+            let deps: Vec<_> = filter.matching_deps(version).collect();
+            let kinds: BTreeSet<_> = deps.iter().map(|dep| kind_name(dep.kind())).collect();
+            for kind in kinds {
+                *by_kind.entry(kind).or_default() += 1;
+            }
+            let reqs: BTreeSet<_> = deps.iter().map(|dep| dep.requirement()).collect();
+            for req in reqs {
+                let req_kind = VersionReq::parse(req)
+                    .map(|req| req::kind_of(&req))
+                    .unwrap_or("unparseable");
+                *requirement_kinds.entry(req_kind).or_default() += 1;
+                *requirements.entry(req).or_default() += 1;
+            }
+        }
+
+        let mut top_requirements: Vec<_> = requirements
+            .into_iter()
+            .map(|(requirement, count)| RequirementCount {
+                requirement: requirement.to_owned(),
+                count,
+            })
+            .collect();
+        // Ties are broken by the requirement to keep the output stable
+        top_requirements.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.requirement.cmp(&b.requirement))
+        });
+        top_requirements.truncate(NUM_TOP_REQUIREMENTS);
+
+        Self {
+            total: dependents.len(),
+            by_kind,
+            requirement_kinds,
+            top_requirements,
+        }
+    }
+
+    pub fn report(&self) -> Result<()> {
+        let dialog = Dialog::summary_with("Stats for {} matching crates", disps![self.total])?;
+        let kind_dialog = dialog.info("By dependency kind")?;
+        for (kind, count) in &self.by_kind {
+            kind_dialog.info_with("{:<8} {:>7}", disps![*kind, *count])?;
+        }
+        let req_kind_dialog = dialog.info("By requirement kind")?;
+        for (kind, count) in &self.requirement_kinds {
+            req_kind_dialog.info_with("{:<12} {:>7}", disps![*kind, *count])?;
+        }
+        let top_dialog = dialog.info("Most common requirements")?;
+        for RequirementCount { requirement, count } in &self.top_requirements {
+            top_dialog.info_with("{:<16} {:>7}", disps![requirement, *count])?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct CrateRecord<'a> {
+    name: &'a str,
+    version: &'a str,
+    download_url: Option<String>,
+    requirements: Vec<RequirementRecord<'a>>,
+}
+
+impl<'a> CrateRecord<'a> {
+    pub fn new(version: &'a VersionExt, config: &IndexConfig, filter: &'a DepFilter) -> Self {
+        Self {
+            name: version.name(),
+            version: version.version(),
+            download_url: version.download_url(config),
+            requirements: RequirementRecord::all(version, filter),
+        }
+    }
+}
+
+// How a matched crate depends on one of the deps
+#[derive(Serialize)]
+pub struct RequirementRecord<'a> {
+    // The actual crate, even when it's renamed locally
+    pub dep: &'a str,
+    pub requirement: &'a str,
+    pub kind: &'static str,
+    pub optional: bool,
+}
+
+impl<'a> RequirementRecord<'a> {
+    pub fn all(version: &'a VersionExt, filter: &'a DepFilter) -> Vec<Self> {
+        filter
+            .matching_deps(version)
+            .map(|dep| Self {
+                dep: dep.crate_name(),
+                requirement: dep.requirement(),
+                kind: kind_name(dep.kind()),
+                optional: dep.is_optional(),
+            })
+            .collect()
+    }
+}
+
+// A version that got skipped due to its dependency requirement failing to parse
+pub struct SkippedVersion {
+    pub version: VersionExt,
+    pub requirement: String,
+}
+
+// Every version considered by the scan that uses one of the deps. `Err`s are versions that had to be
+// skipped. `pb` gets ticked for every crate
+fn scan<'a>(
+    index: &'a Index,
+    filter: &'a DepFilter,
+    select: VersionSelect,
+    pb: ProgressBar,
+) -> impl ParallelIterator<Item = Result<VersionExt, SkippedVersion>> + 'a {
+    index
+        .crates_parallel()
+        .filter_map(|maybe_krate| maybe_krate.ok())
+        .inspect(move |_| pb.inc(1))
+        // Cheaply weeds out crates before looking at any of their versions
+        .filter(|krate| filter.is_scanned(krate.name()))
+        .flat_map_iter(move |krate| filter.matching_versions(krate.versions(), select))
+}
+
+pub fn reverse_dependents_for(
+    index: &Index,
+    filter: &DepFilter,
+    select: VersionSelect,
+    pb: &ProgressBar,
+) -> (Vec<VersionExt>, Vec<SkippedVersion>) {
+    let (matches, skipped): (HashSet<_>, Vec<_>) = scan(index, filter, select, pb.clone())
+        .partition_map(|res| match res {
+            Ok(version) => Either::Left(version),
+            Err(skipped) => Either::Right(skipped),
+        });
+
+    (matches.into_iter().collect(), skipped)
+}
+
+// Like `reverse_dependents_for()`, but yields the results as the scan finds them instead of waiting
+// for it to finish. The scan runs on a background thread, so results come in no particular order
+pub fn stream_reverse_dependents(
+    index: Index,
+    filter: DepFilter,
+    select: VersionSelect,
+) -> impl Iterator<Item = Result<VersionExt, SkippedVersion>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        // Sending only fails once the receiver is gone, so that's our cue to stop scanning
+        let _ = scan(&index, &filter, select, ProgressBar::hidden())
+            .try_for_each_with(tx, |tx, res| tx.send(res).map_err(drop));
+    });
+
+    let mut seen = HashSet::new();
+    rx.into_iter().filter(move |res| match res {
+        Ok(version) => seen.insert(version.clone()),
+        Err(_) => true,
+    })
+}
+
+// The highest version of every crate that uses `dep` through any kind of dependency. Versions with
+// unparseable requirements are left out
+pub fn find_reverse_deps(index: &Index, dep: &str) -> Result<Vec<VersionExt>> {
+    let (matches, _) = reverse_dependents_for(
+        index,
+        &DepFilter::any_kind(dep),
+        VersionSelect::Highest,
+        &ProgressBar::hidden(),
+    );
+    Ok(matches)
+}
+
+// Looks up a single crate's `version`, or when there isn't one the highest version that `filter`'s
+// yank policy and pre-release setting allow, same as a scan would pick
+pub fn find_crate_version(
+    index: &Index,
+    name: &str,
+    version: Option<&str>,
+    filter: &DepFilter,
+) -> Result<VersionExt> {
+    let krate = index
+        .crate_(name)
+        .with_context(|| format!("No crate named `{}` in the index", name))?;
+    let found = match version {
+        Some(version) => krate
+            .versions()
+            .iter()
+            .find(|candidate| candidate.version() == version)
+            .with_context(|| format!("`{}` has no version {} in the index", name, version))?
+            .to_owned(),
+        None => find_highest_version(krate.versions(), filter).with_context(|| {
+            format!(
+                "`{}` has no versions that the yank policy and pre-release setting allow",
+                name
+            )
+        })?,
+    };
+
+    Ok(VersionExt::new(found))
+}
+
+fn find_highest_version(versions: &[Version], filter: &DepFilter) -> Option<Version> {
+    filter
+        .select_versions(versions, VersionSelect::Highest)
+        .pop()
+}
+
+// Same as `find_reverse_deps()`, but streams the matches as they're found
+pub fn iter_reverse_deps(index: Index, dep: &str) -> impl Iterator<Item = VersionExt> {
+    stream_reverse_dependents(index, DepFilter::any_kind(dep), VersionSelect::Highest)
+        .filter_map(Result::ok)
+}
+
+// Builds up a "{}: {}, {}: {}" style breakdown from the labeled counts
+fn breakdown<'a>(counts: impl IntoIterator<Item = (&'a str, usize)>) -> (String, Vec<Disp<'a>>) {
+    let mut template = Vec::new();
+    let mut disps = Vec::new();
+    for (label, count) in counts {
+        template.push("{}: {}");
+        disps.extend(disps![label, count]);
+    }
+
+    (template.join(", "), disps)
+}
+
+fn kind_name(kind: DependencyKind) -> &'static str {
+    match kind {
+        DependencyKind::Normal => "normal",
+        DependencyKind::Dev => "dev",
+        DependencyKind::Build => "build",
+    }
+}
+
+// Progress bars get hidden along with the rest of the chatter when running quietly
+fn new_progress_bar(len: u64) -> ProgressBar {
+    let pb = ProgressBar::new(len);
+    if Verbosity::global() == Verbosity::Quiet {
+        pb.set_draw_target(ProgressDrawTarget::hidden());
+    }
+
+    pb
+}
+
+// Shows a spinner with `msg` while `f` runs. It gets cleared after, so quick calls only flash it
+fn with_spinner<T>(msg: &str, f: impl FnOnce() -> T) -> T {
+    let spinner = new_progress_bar(0).with_style(
+        ProgressStyle::default_spinner()
+            .template("{elapsed:>3.green.bold} {spinner:.blue.bold} {msg:!.bold}"),
+    );
+    spinner.set_message(msg.to_owned());
+    spinner.enable_steady_tick(100);
+    let res = f();
+    spinner.finish_and_clear();
+    res
+}
+
+// Opening the index clones it when it's missing, which can take quite a while. Opens the git index at
+// `url` instead of crates.io's when one is passed, or the existing checkout at `path`
+pub fn open_index(url: Option<&str>, path: Option<&Path>) -> Result<Index> {
+    tracing::debug!(?url, ?path, "opening the index");
+    let index = match (path, url) {
+        (Some(path), _) => open_index_at(path)?,
+        (None, Some(url)) => with_spinner("Updating the index...", || Index::from_url(url))?,
+        (None, None) => with_spinner("Updating crates.io index...", Index::new_cargo_default)?,
+    };
+    tracing::debug!(path = %index.path().display(), "opened the index");
+    Ok(index)
+}
+
+const CRATES_IO_INDEX: &str = "https://github.com/rust-lang/crates.io-index";
+
+// `crates_index` inits and fetches into any path that isn't already a repo for the url it's handed,
+// so this checks it's an index first and hands over the checkout's own origin
+fn open_index_at(path: &Path) -> Result<Index> {
+    let dialog = Dialog::raw_with_indent(NonZeroUsize::new(1).unwrap());
+    let hint = "Pass the root of an index checkout like a clone of crates.io's";
+    let repo = match git2::Repository::open(path) {
+        Ok(repo) => repo,
+        Err(e) => {
+            dialog
+                .error_with("{} isn't a git repo: {}", disps![path, e.message()])?
+                .error(hint)?;
+            anyhow::bail!("Couldn't open the index at {}", path.display());
+        }
+    };
+    // Cargo's own checkouts don't set an origin, which `crates_index` accepts with any url
+    let url = repo
+        .find_remote("origin")
+        .ok()
+        .and_then(|origin| origin.url().map(ToOwned::to_owned))
+        .unwrap_or_else(|| CRATES_IO_INDEX.to_owned());
+
+    let index = Index::with_path(path, url)?;
+    if index.index_config().is_err() {
+        dialog
+            .error_with(
+                "{} doesn't have a config.json, so it isn't an index",
+                disps![path],
+            )?
+            .error(hint)?;
+        anyhow::bail!("Couldn't open the index at {}", path.display());
+    }
+
+    Ok(index)
+}
+
+// `num_crates` is how many crates the index had last time, if known. Returns the matches along with
+// how many crates were scanned
+fn find_dependents(
+    index: &Index,
+    filter: &DepFilter,
+    select: VersionSelect,
+    num_crates: Option<u64>,
+) -> Result<(Vec<VersionExt>, u64)> {
+    let deps_list = filter
+        .names
+        .iter()
+        .map(|dep| format!("`{}`", dep))
+        .collect::<Vec<_>>()
+        .join(", ");
+    // Crates get added over time, so a previous count is only ever an estimate, but it's close enough
+    // for showing progress
+    let pb = match num_crates {
+        Some(num_crates) => new_progress_bar(num_crates).with_style(
+            ProgressStyle::default_bar()
+                .template(&format!(
+                    "{{elapsed:>3.green.bold}} {{msg:!.bold}} \
+                     {}{{bar:40.blue}}{} {} {{eta:<3.green.bold}}",
+                    "|".bold(),
+                    "|".bold(),
+                    "eta".green().bold(),
+                ))
+                .progress_chars("█▉▊▋▌▍▎▏ "),
+        ),
+        None => new_progress_bar(0).with_style(ProgressStyle::default_spinner().template(
+            "{elapsed:>3.green.bold} {spinner:.blue.bold} {msg:!.bold} {pos:.cyan.bold} crates",
+        )),
+    };
+    pb.set_message(dialog::fmt_with(
+        "Finding all current crates that use {}...",
+        disps![&deps_list],
+    )?);
+    pb.enable_steady_tick(100);
+    let start = Instant::now();
+    let (uses_dep, skipped) = reverse_dependents_for(index, filter, select, &pb);
+    pb.finish();
+    tracing::debug!(
+        num_crates = pb.position(),
+        num_matches = uses_dep.len(),
+        elapsed = ?start.elapsed(),
+        "scan finished"
+    );
+    let found_dialog = Dialog::raw_with_indent(NonZeroUsize::new(1).unwrap()).info_with(
+        "Found {} crates that use {}!",
+        disps![uses_dep.len(), deps_list],
+    )?;
+    // A version that uses several of the deps (or a dep via several kinds) gets counted under
+    // each of them
+    let count_where = |pred: &dyn Fn(&VersionExt) -> Result<bool, String>| {
+        uses_dep
+            .iter()
+            .filter(|version| pred(version).unwrap_or(false))
+            .count()
+    };
+    let (template, disps) = breakdown(filter.names.iter().map(|name| {
+        let count = count_where(&|version| filter.uses(version, name));
+        (name.as_str(), count)
+    }));
+    found_dialog.info_with(&template, disps)?;
+    let (template, disps) = breakdown(filter.kinds.iter().map(|&kind| {
+        let count = count_where(&|version| filter.uses_via(version, kind));
+        (kind_name(kind), count)
+    }));
+    found_dialog.info_with(&format!("Matched via {}", template), disps)?;
+    if filter.include_optional {
+        let optional_only = count_where(&|version| filter.uses_only_optionally(version));
+        found_dialog.info_with(
+            "{} only use them as optional dependencies",
+            disps![optional_only],
+        )?;
+    }
+    for SkippedVersion {
+        version,
+        requirement,
+    } in skipped
+    {
+        found_dialog.warn_with(
+            "Skipped {} due to unparseable requirement {}",
+            disps![&version, requirement],
+        )?;
+    }
+
+    Ok((uses_dep, pb.position()))
+}
+
+// Updates the index unless the last forced update was too recent. Returns whether it updated
+pub fn try_force_update_index(
+    index: &mut Index,
+    state: &mut State,
+    min_interval: Duration,
+) -> Result<bool> {
+    if let Some(since) = state.since_forced_update() {
+        if since < min_interval {
+            let remaining = min_interval - since;
+            Dialog::raw_with_indent(NonZeroUsize::new(1).unwrap()).warn_with(
+                "The index was force updated recently. Skipping for another {}",
+                disps![remaining],
+            )?;
+            return Ok(false);
+        }
+    }
+
+    tracing::debug!(path = %index.path().display(), "index update started");
+    with_spinner("Updating crates.io index...", || index.update())?;
+    tracing::debug!("index update finished");
+    Dialog::new("Updated the index")?;
+    state.record_forced_update();
+    if let Err(e) = state.save() {
+        Dialog::raw_with_indent(NonZeroUsize::new(1).unwrap())
+            .warn_with("Failed saving the update time: {}", disps![e])?;
+    }
+
+    Ok(true)
+}
+
+// Scanning the whole index is slow, so this reuses the last results when we can. Also returns how
+// many crates were scanned, if known
+pub fn load_or_find_dependents(
+    index: &Index,
+    cache_path: &Path,
+    filter: &DepFilter,
+    select: VersionSelect,
+    force_rescan: bool,
+    max_age: Duration,
+) -> Result<(Vec<VersionExt>, Option<u64>)> {
+    let options = format!("{:?} {:?}", filter, select);
+    let found = match ScanRecord::load(cache_path) {
+        Some(record) if !force_rescan && record.is_fresh(index, &options, max_age) => {
+            Dialog::new_with(
+                "Loaded {} matching crates from the previous scan",
+                disps![record.matches.len()],
+            )?;
+            let dependents = record.matches.into_iter().map(VersionExt::from).collect();
+            (dependents, record.num_crates)
+        }
+        stale => {
+            let prev_num_crates = stale.and_then(|record| record.num_crates);
+            let (dependents, num_crates) = find_dependents(index, filter, select, prev_num_crates)?;
+            let matches = dependents.iter().map(|v| v.inner().to_owned()).collect();
+            let record = ScanRecord::new(index, options, matches, num_crates);
+            if let Err(e) = record.save(cache_path) {
+                Dialog::raw_with_indent(NonZeroUsize::new(1).unwrap())
+                    .warn_with("Failed saving the scan results: {}", disps![e])?;
+            }
+            (dependents, Some(num_crates))
+        }
+    };
+
+    Ok(found)
+}
+
+// A matched crate that's already extracted locally along with the snapshots found in it
+pub struct LocalCrate {
+    pub version: VersionExt,
+    pub dir: PathBuf,
+    pub snapshots: Vec<ExtractedSnapshot>,
+}
+
+// Splits `versions` into the ones that still need to be downloaded and the ones that are already
+// extracted locally
+pub fn get_uninstalled(
+    registry: &CargoRegistry,
+    out_dir: &Path,
+    versions: Vec<VersionExt>,
+    globs: &SnapshotGlobs,
+    listings: &Listings,
+) -> Result<(Vec<VersionExt>, Vec<LocalCrate>)> {
+    // Check to see which ones we already have installed
+    let scan_dialog = Dialog::new("Scanning locally downloaded crates...")?;
+    let skipping = FmtStr::parse("Skipping {} since it's already downloaded ({} snapshots)")?;
+    let local_crates = LocalCrates::new(&[out_dir.to_owned(), registry.src()])?;
+    // Walking the crates is the slow part, so that happens on the pool before reporting in order
+    let found: Vec<_> = versions
+        .into_par_iter()
+        .map(|version| {
+            let found = local_crates.dir_of(&version).map(|dir| {
+                (
+                    listed_snapshots(&dir, version.checksum(), globs, listings),
+                    dir,
+                )
+            });
+            (version, found)
+        })
+        .collect();
+    let mut to_download = Vec::new();
+    let mut local = Vec::new();
+    for (version, found) in found {
+        match found {
+            Some((snapshots, dir)) => {
+                let crate_dialog =
+                    scan_dialog.detail_fmt(&skipping, disps![&version, snapshots.len()])?;
+                for snapshot in &snapshots {
+                    crate_dialog.detail_with("{}", disps![dir.join(&snapshot.path)])?;
+                }
+                local.push(LocalCrate {
+                    version,
+                    dir,
+                    snapshots,
+                });
+            }
+            None => to_download.push(version),
+        }
+    }
+    if to_download.is_empty() {
+        scan_dialog.info("No crates to download!")?;
+    } else {
+        scan_dialog.info_with("{} crates to download", disps![to_download.len()])?;
+    }
+
+    Ok((to_download, local))
+}
+
+pub const DEFAULT_SNAPSHOT_GLOBS: &[&str] = &["**/*.snap", "**/*.snap.new"];
+
+// Decides which files within a crate count as snapshots
+#[derive(Debug, Clone)]
+pub struct SnapshotGlobs {
+    set: GlobSet,
+    // Kept around so that listings can tell which globs they were made with
+    patterns: Vec<String>,
+}
+
+impl SnapshotGlobs {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            let glob = Glob::new(pattern)
+                .with_context(|| format!("Invalid snapshot glob {:?}", pattern))?;
+            builder.add(glob);
+        }
+        Ok(Self {
+            set: builder.build()?,
+            patterns: patterns.iter().map(|p| p.as_ref().to_owned()).collect(),
+        })
+    }
+
+    // `path` is relative to the crate's root
+    fn is_snapshot(&self, path: &Path) -> bool {
+        self.set.is_match(path)
+    }
+}
+
+impl Default for SnapshotGlobs {
+    fn default() -> Self {
+        Self::new(DEFAULT_SNAPSHOT_GLOBS).unwrap()
+    }
+}
+
+// Recursively collects all of the snapshot files within the crate at `dir` ignoring any errors
+fn snapshots_in(dir: &Path, globs: &SnapshotGlobs) -> Vec<PathBuf> {
+    let mut snapshots = Vec::new();
+    let root = dir;
+    let mut to_visit = vec![dir.to_owned()];
+    while let Some(dir) = to_visit.pop() {
+        let entries = match dir.read_dir() {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => to_visit.push(path),
+                Ok(file_type)
+                    if file_type.is_file()
+                        && path
+                            .strip_prefix(root)
+                            .is_ok_and(|rel_path| globs.is_snapshot(rel_path)) =>
+                {
+                    snapshots.push(path)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    snapshots
+}
+
+// Like `snapshots_in()`, but relative to the root of the extracted crate at `crate_dir`
+fn snapshots_in_crate(crate_dir: &Path, globs: &SnapshotGlobs) -> Vec<ExtractedSnapshot> {
+    let mut snapshots: Vec<_> = snapshots_in(crate_dir, globs)
+        .into_iter()
+        .filter_map(|path| {
+            let size = path.metadata().ok()?.len();
+            Some(ExtractedSnapshot {
+                path: path.strip_prefix(crate_dir).ok()?.to_owned(),
+                size,
+                duplicate_of: None,
+            })
+        })
+        .collect();
+    snapshots.sort_by(|a, b| a.path.cmp(&b.path));
+    snapshots
+}
+
+// Like `snapshots_in_crate()`, but reuses the listing from an earlier run when neither the crate's
+// dirs nor any of its snapshots changed since. Otherwise the crate gets walked again and listed for
+// next time
+fn listed_snapshots(
+    crate_dir: &Path,
+    checksum: &[u8; 32],
+    globs: &SnapshotGlobs,
+    listings: &Listings,
+) -> Vec<ExtractedSnapshot> {
+    if let Some(listing) = listings.load(checksum) {
+        if listing.globs == globs.patterns
+            && TreeStamp::new(crate_dir).as_ref() == Some(&listing.tree)
+            && listing
+                .snapshots
+                .iter()
+                .all(|snapshot| snapshot.is_unchanged(crate_dir))
+        {
+            return listing
+                .snapshots
+                .into_iter()
+                .map(|snapshot| ExtractedSnapshot {
+                    path: snapshot.path,
+                    size: snapshot.size,
+                    duplicate_of: None,
+                })
+                .collect();
+        }
+    }
+
+    relist_snapshots(crate_dir, checksum, globs, listings)
+}
+
+// Walks the crate and saves the listing. Failing to save only costs the next run a walk
+fn relist_snapshots(
+    crate_dir: &Path,
+    checksum: &[u8; 32],
+    globs: &SnapshotGlobs,
+    listings: &Listings,
+) -> Vec<ExtractedSnapshot> {
+    // Stamped before the walk so that anything changing during it gets caught next time
+    let tree = TreeStamp::new(crate_dir);
+    let snapshots = snapshots_in_crate(crate_dir, globs);
+    let listed = snapshots
+        .iter()
+        .map(|snapshot| {
+            let sha256 = sha256_of(&crate_dir.join(&snapshot.path)).ok()?;
+            ListedSnapshot::new(crate_dir, snapshot.path.clone(), &sha256)
+        })
+        .collect::<Option<_>>();
+    if let (Some(tree), Some(listed)) = (tree, listed) {
+        let listing = Listing {
+            globs: globs.patterns.clone(),
+            tree,
+            snapshots: listed,
+        };
+        if let Err(e) = listings.save(checksum, &listing) {
+            tracing::debug!(error = %e, "couldn't save the listing");
+        }
+    }
+
+    snapshots
+}
+
+// Searches through the already extracted crates without touching the network
+pub fn scan_local_snapshots(
+    registry: &CargoRegistry,
+    out_dir: &Path,
+    globs: &SnapshotGlobs,
+) -> Result<()> {
+    let scan_dialog = Dialog::new("Searching locally extracted crates for snapshots...")?;
+    let local_crates = LocalCrates::new(&[out_dir.to_owned(), registry.src()])?;
+    // Same as in `get_uninstalled()` the walks happen on the pool first
+    let dirs: Vec<_> = local_crates.dirs().collect();
+    let found: Vec<_> = dirs
+        .into_par_iter()
+        .map(|(name, dir)| (name, snapshots_in(&dir, globs)))
+        .collect();
+    let mut num_snapshots = 0;
+    let mut num_crates = 0;
+    for (name, snapshots) in found {
+        if snapshots.is_empty() {
+            continue;
+        }
+
+        let crate_dialog =
+            scan_dialog.info_with("{} has {} snapshots", disps![name, snapshots.len()])?;
+        for snapshot in &snapshots {
+            crate_dialog.detail_with("{}", disps![snapshot.as_path()])?;
+        }
+        num_snapshots += snapshots.len();
+        num_crates += 1;
+    }
+
+    Dialog::summary_with(
+        "Found {} snapshots across {} crates",
+        disps![num_snapshots, num_crates],
+    )?;
+
+    Ok(())
+}
+
+// The fully extracted crates in `out_dir` where the crate isn't in `matched_names` anymore, which
+// means that it dropped the deps since it was pulled. Crates that `filter` doesn't scan are left
+// alone since there's no telling. So is cargo's registry since those crates aren't ours to remove
+pub fn stale_crates(
+    out_dir: &Path,
+    filter: &DepFilter,
+    matched_names: &HashSet<&str>,
+) -> Result<Vec<(String, PathBuf)>> {
+    let local_crates = LocalCrates::new(&[out_dir.to_owned()])?;
+    let stale = local_crates
+        .dirs()
+        .filter(|(dir_name, dir)| {
+            parse_dir_name(dir_name)
+                .is_some_and(|(name, _)| filter.is_scanned(name) && !matched_names.contains(name))
+                && is_fully_extracted(dir)
+        })
+        .map(|(dir_name, dir)| (dir_name.to_owned(), dir))
+        .collect();
+    Ok(stale)
+}
+
+// Lists the `stale_crates()` and removes them when `prune`ing
+pub fn report_stale(
+    out_dir: &Path,
+    filter: &DepFilter,
+    matched: &[VersionExt],
+    prune: bool,
+) -> Result<()> {
+    let matched_names = matched.iter().map(|version| version.name()).collect();
+    let stale = stale_crates(out_dir, filter, &matched_names)?;
+    let dialog = Dialog::new_with(
+        "Found {} local crates that don't use the deps anymore",
+        disps![stale.len()],
+    )?;
+    for (dir_name, dir) in &stale {
+        if prune {
+            fs::remove_dir_all(dir)
+                .with_context(|| format!("Failed removing stale crate {}", dir.display()))?;
+            dialog.info_with("Removed {}", disps![dir_name])?;
+        } else {
+            dialog.info_with("{}", disps![dir_name])?;
+        }
+    }
+
+    Ok(())
+}
+
+// Rejects anything that could escape the directory we're extracting into
+fn is_safe_entry<R: Read>(entry: &Entry<'_, R>) -> bool {
+    let entry_type = entry.header().entry_type();
+    if entry_type.is_symlink() || entry_type.is_hard_link() {
+        return false;
+    }
+
+    match entry.path() {
+        Ok(path) => path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir)),
+        Err(_) => false,
+    }
+}
+
+// What got pulled out of a single archive
+#[derive(Debug, Default)]
+struct Extraction {
+    snapshots: Vec<ExtractedSnapshot>,
+    // Entries that were refused for trying to escape the dir or being a link
+    rejected: Vec<String>,
+    // Hashes of the snapshots written out for `Dedup`, which only get committed once the crate
+    // checks out
+    written: HashMap<[u8; 32], PathBuf>,
+}
+
+// Unpacks only the snapshot files from the archive into `dest`. Everything else is skipped without
+// ever touching the disk
+fn extract_snapshots<R: Read>(
+    archive: &mut Archive<R>,
+    dest: &Path,
+    globs: &SnapshotGlobs,
+    dedup: Option<&Dedup>,
+) -> Result<Extraction> {
+    let mut extraction = Extraction::default();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !is_safe_entry(&entry) {
+            // The raw bytes since the path might not even be valid
+            let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+            extraction.rejected.push(path);
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        // Drops the leading `cratename-version/`
+        let rel_path: PathBuf = path.components().skip(1).collect();
+        if !globs.is_snapshot(&rel_path) {
+            continue;
+        }
+
+        let duplicate_of = match dedup {
+            Some(dedup) => {
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents)?;
+                let hash: [u8; 32] = Sha256::digest(&contents).into();
+                let duplicate_of = dedup.find(&hash, &extraction.written);
+                if duplicate_of.is_none() {
+                    // Safe to join since `is_safe_entry()` only allows normal components
+                    let out_path = dest.join(&path);
+                    if let Some(parent) = out_path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::write(out_path, &contents)?;
+                    extraction.written.insert(hash, path.clone());
+                }
+                duplicate_of
+            }
+            None => {
+                entry.unpack_in(dest)?;
+                None
+            }
+        };
+        extraction.snapshots.push(ExtractedSnapshot {
+            path: rel_path,
+            size: entry.size(),
+            duplicate_of,
+        });
+    }
+
+    Ok(extraction)
+}
+
+// Tracks the hashes of every snapshot extracted during the run, so that identical ones only get
+// written once
+#[derive(Default)]
+struct Dedup {
+    // The hash maps to where the first copy was written, relative to the out dir
+    seen: Mutex<HashMap<[u8; 32], PathBuf>>,
+}
+
+impl Dedup {
+    // Where the original of a snapshot with `hash` lives, either from an already committed crate or
+    // from earlier in the crate currently being extracted
+    fn find(&self, hash: &[u8; 32], written: &HashMap<[u8; 32], PathBuf>) -> Option<PathBuf> {
+        let seen = self.seen.lock().unwrap();
+        seen.get(hash).or_else(|| written.get(hash)).cloned()
+    }
+
+    // Makes a verified crate's snapshots available as originals for the rest of the run
+    fn commit(&self, written: HashMap<[u8; 32], PathBuf>) {
+        let mut seen = self.seen.lock().unwrap();
+        for (hash, path) in written {
+            seen.entry(hash).or_insert(path);
+        }
+    }
+}
+
+// A line in the sidecar index that records the snapshot files elided by `--dedup`
+#[derive(Serialize, Deserialize)]
+struct DuplicateRecord {
+    // Both are relative to the out dir
+    path: String,
+    duplicate_of: PathBuf,
+}
+
+const DUPLICATES_FILE: &str = "duplicates.jsonl";
+
+// The index covers every run into `dest`, so the records from earlier runs are kept unless this
+// run pulled the same snapshot again. Returns how many duplicates this run elided
+fn write_duplicates(dest: &Path, pulled: &[(&Download, Vec<ExtractedSnapshot>)]) -> Result<usize> {
+    let path = dest.join(DUPLICATES_FILE);
+    let mut records = Vec::new();
+    let mut pulled_paths = HashSet::new();
+    for (download, snapshots) in pulled {
+        for snapshot in snapshots {
+            let record_path = Path::new(&download.version.dir_name())
+                .join(&snapshot.path)
+                .to_string_lossy()
+                .into_owned();
+            if let Some(duplicate_of) = &snapshot.duplicate_of {
+                records.push(DuplicateRecord {
+                    path: record_path.clone(),
+                    duplicate_of: duplicate_of.to_owned(),
+                });
+            }
+            pulled_paths.insert(record_path);
+        }
+    }
+    let num_duplicates = records.len();
+
+    let previous = match File::open(&path) {
+        Ok(file) => BufReader::new(file)
+            .lines()
+            .map(|line| Ok(serde_json::from_str::<DuplicateRecord>(&line?)?))
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("Failed reading duplicates index {}", path.display()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let previous = previous
+        .into_iter()
+        .filter(|record| !pulled_paths.contains(&record.path));
+
+    let mut writer = BufWriter::new(
+        File::create(&path)
+            .with_context(|| format!("Failed creating duplicates index {}", path.display()))?,
+    );
+    for record in previous.chain(records) {
+        serde_json::to_writer(&mut writer, &record)?;
+        writeln!(writer)?;
+    }
+
+    writer.flush()?;
+    Ok(num_duplicates)
+}
+
+// A snapshot file that got extracted from a crate
+#[derive(Debug, Clone)]
+pub struct ExtractedSnapshot {
+    // Relative to the crate's root
+    pub path: PathBuf,
+    pub size: u64,
+    // Set when the file wasn't written since it's identical to this already extracted snapshot
+    pub duplicate_of: Option<PathBuf>,
+}
+
+impl ExtractedSnapshot {
+    // Where the contents live on disk. Deduped snapshots only exist as the original in `dest`
+    fn location(&self, crate_dir: &Path, dest: &Path) -> PathBuf {
+        match &self.duplicate_of {
+            Some(original) => dest.join(original),
+            None => crate_dir.join(&self.path),
+        }
+    }
+}
+
+// Copies all of the snapshots into one flat dir. Returns how many were copied
+fn collect_snapshots<'a>(
+    collect_dir: &Path,
+    dest: &Path,
+    crates: impl Iterator<Item = (&'a VersionExt, PathBuf, &'a [ExtractedSnapshot])>,
+) -> Result<usize> {
+    fs::create_dir_all(collect_dir)
+        .with_context(|| format!("Failed creating collect dir {}", collect_dir.display()))?;
+    let mut used = HashSet::new();
+    let mut num_collected = 0;
+    for (version, crate_dir, snapshots) in crates {
+        for snapshot in snapshots {
+            let name = collected_name(&version.dir_name(), &snapshot.path, &mut used);
+            let from = snapshot.location(&crate_dir, dest);
+            fs::copy(&from, collect_dir.join(name))
+                .with_context(|| format!("Failed collecting {}", from.display()))?;
+            num_collected += 1;
+        }
+    }
+
+    Ok(num_collected)
+}
+
+// Flattens `path` into a name like `cratename-version__tests_snapshots_a.snap`. Anything that
+// collides with a name in `used` gets a counter added before the file's extension
+fn collected_name(dir_name: &str, path: &Path, used: &mut HashSet<String>) -> String {
+    let flattened = path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("_");
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let ext = file_name.find('.').map_or("", |dot| &file_name[dot..]);
+    let stem = &flattened[..flattened.len() - ext.len()];
+
+    let mut name = format!("{}__{}", dir_name, flattened);
+    let mut counter = 1;
+    while used.contains(&name) {
+        name = format!("{}__{}-{}{}", dir_name, stem, counter, ext);
+        counter += 1;
+    }
+    used.insert(name.clone());
+    name
+}
+
+// A line in the `--json-lines` output
+#[derive(Serialize)]
+struct CrateEvent<'a> {
+    name: &'a str,
+    version: &'a str,
+    status: CrateStatus,
+    snapshots: usize,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum CrateStatus {
+    Downloaded,
+    Cached,
+    Local,
+    Failed,
+    #[serde(rename = "too_large")]
+    TooLarge,
+}
+
+// Each event is written with a single locked `println!()`, so lines from different threads can't
+// get interleaved
+fn emit_event(version: &VersionExt, status: CrateStatus, snapshots: usize) -> Result<()> {
+    let event = CrateEvent {
+        name: version.name(),
+        version: version.version(),
+        status,
+        snapshots,
+    };
+    println!("{}", serde_json::to_string(&event)?);
+    Ok(())
+}
+
+// A line in the `--manifest`
+#[derive(Serialize)]
+struct ManifestRecord<'a> {
+    name: &'a str,
+    version: &'a str,
+    path: String,
+    size: u64,
+}
+
+fn write_manifest<'a>(
+    path: &Path,
+    crates: impl Iterator<Item = (&'a VersionExt, &'a [ExtractedSnapshot])>,
+) -> Result<()> {
+    let mut writer = BufWriter::new(
+        File::create(path)
+            .with_context(|| format!("Failed creating manifest {}", path.display()))?,
+    );
+    for (version, snapshots) in crates {
+        for snapshot in snapshots {
+            let record = ManifestRecord {
+                name: version.name(),
+                version: version.version(),
+                path: snapshot.path.to_string_lossy().into_owned(),
+                size: snapshot.size,
+            };
+            serde_json::to_writer(&mut writer, &record)?;
+            writeln!(writer)?;
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+pub struct Download {
+    pub version: VersionExt,
+    pub url: String,
+}
+
+fn sha256_of(path: &Path) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+impl Download {
+    // Matches the name cargo uses for the `.crate` in its cache
+    fn file_name(&self) -> String {
+        format!("{}.crate", self.version.dir_name())
+    }
+
+    fn is_valid_at(&self, path: &Path) -> bool {
+        path.exists() && sha256_of(path).is_ok_and(|sum| sum == *self.version.checksum())
+    }
+}
+
+// The index doesn't record how big each `.crate` is, so sizes are only known for the ones already
+// in `crate_dir`. Returns the total size of those along with how many there were
+pub fn cached_size(crate_dir: &Path, downloads: &[Download]) -> (u64, usize) {
+    downloads
+        .iter()
+        .filter_map(|download| fs::metadata(crate_dir.join(download.file_name())).ok())
+        .fold((0, 0), |(bytes, num_cached), meta| {
+            (bytes + meta.len(), num_cached + 1)
+        })
+}
+
+// Drops the crates that are known to be over `max_size`, which again only covers the ones in
+// `crate_dir`. The rest get cut off while downloading instead. Returns how many got dropped
+pub fn skip_too_large(
+    crate_dir: &Path,
+    downloads: &mut Vec<Download>,
+    max_size: u64,
+) -> Result<usize> {
+    let mut too_large = Vec::new();
+    downloads.retain(
+        |download| match fs::metadata(crate_dir.join(download.file_name())) {
+            Ok(meta) if meta.len() > max_size => {
+                too_large.push((download.file_name(), meta.len()));
+                false
+            }
+            _ => true,
+        },
+    );
+    if !too_large.is_empty() {
+        let dialog = Dialog::new_with(
+            "Skipping {} crates over the max crate size of {}",
+            disps![too_large.len(), Bytes(max_size)],
+        )?;
+        for (file_name, size) in &too_large {
+            dialog.detail_with("{} is {}", disps![file_name, Bytes(*size)])?;
+        }
+    }
+
+    Ok(too_large.len())
+}
+
+// Bounds on how long we're willing to wait when being rate limited
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(5 * 60);
+const MAX_RATE_LIMIT_WAITS: usize = 10;
+// Used when the server doesn't give us a usable `Retry-After`
+const DEFAULT_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+// How long the server asked us to wait for. Only the delay-seconds form is supported
+fn retry_after(resp: &ureq::Response) -> Duration {
+    resp.header("Retry-After")
+        .and_then(|val| val.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_RATE_LIMIT_WAIT)
+        .clamp(Duration::from_secs(1), MAX_RATE_LIMIT_WAIT)
+}
+
+// Publish times and download counts aren't in the index, so they have to come from the API instead
+const CRATES_IO_API: &str = "https://crates.io/api/v1/crates";
+
+#[derive(Deserialize)]
+struct ApiVersionResp {
+    version: ApiVersion,
+}
+
+#[derive(Deserialize)]
+struct ApiVersion {
+    // An RFC 3339 timestamp in UTC
+    created_at: String,
+    downloads: u64,
+}
+
+// What `--with-crates-io-meta` looks up for a single version
+#[derive(Debug, Clone)]
+pub struct CratesIoMeta {
+    // `YYYY-MM-DDTHH:MM:SS` in UTC, so comparing the text works just as well as comparing the times
+    pub published: String,
+    pub downloads: u64,
+}
+
+// Looks up each of `versions` with the crates.io API, keyed by their `dir_name()`. Versions where
+// the lookup fails are missing, which `filtering` says gets them left out rather than sorted last
+pub fn crates_io_meta(
+    versions: &[VersionExt],
+    filtering: bool,
+    user_agent: &str,
+    retries: usize,
+) -> Result<HashMap<String, CratesIoMeta>> {
+    let dialog = Dialog::new_with(
+        "Looking up {} crates with the crates.io API...",
+        disps![versions.len()],
+    )?;
+    let agent = ureq::builder().user_agent(user_agent).build();
+    let limiter = RateLimiter::new(Duration::from_millis(MIN_REQUEST_DELAY_MS));
+    let pb = new_progress_bar(versions.len() as u64);
+    let dialog = dialog.with_progress_bar(pb.clone());
+    let missing = if filtering {
+        "Leaving out {}"
+    } else {
+        "Sorting {} last"
+    };
+    let unparsed = FmtStr::parse(&format!(
+        "{} since its publish time {{}} couldn't be parsed",
+        missing
+    ))?;
+    let not_found = FmtStr::parse(&format!("{} since it couldn't be looked up: {{}}", missing))?;
+
+    let mut found = HashMap::new();
+    for version in versions {
+        let url = format!("{}/{}/{}", CRATES_IO_API, version.name(), version.version());
+        let resp = call_with_retries(&agent, &limiter, &url, retries, &dialog).and_then(|resp| {
+            Ok(serde_json::from_reader::<_, ApiVersionResp>(
+                resp.into_reader(),
+            )?)
+        });
+        match resp {
+            Ok(ApiVersionResp {
+                version:
+                    ApiVersion {
+                        created_at,
+                        downloads,
+                    },
+            }) if created_at.len() >= RFC3339_SECONDS_LEN => {
+                let meta = CratesIoMeta {
+                    published: created_at[..RFC3339_SECONDS_LEN].to_owned(),
+                    downloads,
+                };
+                found.insert(version.dir_name(), meta);
+            }
+            Ok(resp) => {
+                dialog.warn_fmt(&unparsed, disps![version, resp.version.created_at])?;
+            }
+            Err(e) => {
+                dialog.warn_fmt(&not_found, disps![version, e])?;
+            }
+        }
+        pb.inc(1);
+    }
+    pb.finish_and_clear();
+
+    Ok(found)
+}
+
+// Only keeps the versions that were published at or after `since` according to `meta`. Versions
+// missing from it get skipped
+pub fn published_since(
+    versions: Vec<VersionExt>,
+    since: SystemTime,
+    meta: &HashMap<String, CratesIoMeta>,
+) -> Result<Vec<VersionExt>> {
+    let cutoff = humantime::format_rfc3339_seconds(since).to_string();
+    let cutoff = &cutoff[..RFC3339_SECONDS_LEN];
+    let num_versions = versions.len();
+    let kept: Vec<_> = versions
+        .into_iter()
+        .filter(|version| {
+            meta.get(&version.dir_name())
+                .is_some_and(|meta| meta.published.as_str() >= cutoff)
+        })
+        .collect();
+
+    Dialog::new_with(
+        "Kept {} of {} crates published since {}",
+        disps![kept.len(), num_versions, cutoff],
+    )?;
+    Ok(kept)
+}
+
+// How many later versions of the same crate were published after each of `versions`, going by the
+// index's version order and keyed by their `dir_name()`. Crates missing from the index are left out
+pub fn newer_versions(index: &Index, versions: &[VersionExt]) -> HashMap<String, usize> {
+    let mut found = HashMap::new();
+    let names: BTreeSet<_> = versions.iter().map(|version| version.name()).collect();
+    for krate in names.into_iter().filter_map(|name| index.crate_(name)) {
+        let published = krate.versions();
+        for (i, version) in published.iter().enumerate() {
+            let dir_name = format!("{}-{}", version.name(), version.version());
+            found.insert(dir_name, published.len() - 1 - i);
+        }
+    }
+
+    found
+}
+
+// The length of `YYYY-MM-DDTHH:MM:SS`
+const RFC3339_SECONDS_LEN: usize = 19;
+
+// Retries failed requests with an exponential backoff starting at one second, so we still respect
+// the crawling policy. Being rate limited waits for as long as the server asks instead and doesn't
+// count against the retries
+fn call_with_retries(
+    agent: &Agent,
+    limiter: &RateLimiter,
+    url: &str,
+    retries: usize,
+    dialog: &Dialog,
+) -> Result<ureq::Response> {
+    let mut backoff = Duration::from_secs(1);
+    let mut attempt = 0;
+    let mut rate_limit_waits = 0;
+    loop {
+        limiter.wait();
+        let start = Instant::now();
+        let result = agent.get(url).call();
+        tracing::debug!(url, elapsed = ?start.elapsed(), ok = result.is_ok(), "request finished");
+        match result {
+            Ok(resp) => return Ok(resp),
+            Err(ureq::Error::Status(429 | 503, resp))
+                if rate_limit_waits < MAX_RATE_LIMIT_WAITS =>
+            {
+                rate_limit_waits += 1;
+                let wait = retry_after(&resp);
+                tracing::debug!(url, ?wait, rate_limit_waits, "rate limited");
+                dialog.info_with("Rate limited, waiting {}", disps![wait])?;
+                sleep(wait);
+            }
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                tracing::debug!(url, attempt, retries, error = %e, "retrying request");
+                dialog.warn_with(
+                    "Request failed ({}), retrying in {} ({}/{})",
+                    disps![anyhow::Error::from(e), backoff, attempt, retries],
+                )?;
+                sleep(backoff);
+                backoff *= 2;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Hashes everything that gets read through it, optionally saving a copy along the way
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    copy: Option<File>,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R, copy: Option<File>) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            copy,
+        }
+    }
+
+    // Reads whatever's left, so that the hash covers all of the input
+    fn finish(mut self) -> Result<[u8; 32]> {
+        io::copy(&mut self, &mut io::sink())?;
+        if let Some(copy) = &mut self.copy {
+            copy.flush()?;
+        }
+        Ok(self.hasher.finalize().into())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        if let Some(copy) = &mut self.copy {
+            copy.write_all(&buf[..len])?;
+        }
+        Ok(len)
+    }
+}
+
+// How far along each in-flight download is. This gets shown alongside the download bar, since it
+// only moves once per crate and a single big crate would otherwise look stalled
+#[derive(Default)]
+struct Transfers {
+    // The bytes received so far and the total, if the server told us
+    in_flight: Mutex<BTreeMap<String, (u64, Option<u64>)>>,
+}
+
+impl Transfers {
+    fn update(
+        &self,
+        pb: &ProgressBar,
+        update: impl FnOnce(&mut BTreeMap<String, (u64, Option<u64>)>),
+    ) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        update(&mut in_flight);
+        let msg = in_flight
+            .iter()
+            .map(|(name, (received, total))| match total {
+                Some(total) => format!("{} {}/{}", name, HumanBytes(*received), HumanBytes(*total)),
+                None => format!("{} {}", name, HumanBytes(*received)),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        pb.set_message(msg);
+    }
+}
+
+// Reports everything read through it to `transfers` until it's dropped
+struct TransferReader<'a, R> {
+    inner: R,
+    name: String,
+    transfers: &'a Transfers,
+    pb: &'a ProgressBar,
+}
+
+impl<'a, R> TransferReader<'a, R> {
+    fn new(
+        inner: R,
+        name: String,
+        total: Option<u64>,
+        transfers: &'a Transfers,
+        pb: &'a ProgressBar,
+    ) -> Self {
+        transfers.update(pb, |in_flight| {
+            in_flight.insert(name.clone(), (0, total));
+        });
+        Self {
+            inner,
+            name,
+            transfers,
+            pb,
+        }
+    }
+}
+
+impl<R: Read> Read for TransferReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.transfers.update(self.pb, |in_flight| {
+            if let Some((received, _)) = in_flight.get_mut(&self.name) {
+                *received += len as u64;
+            }
+        });
+        Ok(len)
+    }
+}
+
+impl<R> Drop for TransferReader<'_, R> {
+    fn drop(&mut self) {
+        self.transfers.update(self.pb, |in_flight| {
+            in_flight.remove(&self.name);
+        });
+    }
+}
+
+// Errors out once more than `limit` bytes get read, flagging `exceeded` so that the error can be told
+// apart from the many others that extracting could end in
+struct SizeLimit<'a, R> {
+    inner: R,
+    remaining: u64,
+    exceeded: &'a Cell<bool>,
+}
+
+impl<R: Read> Read for SizeLimit<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        match self.remaining.checked_sub(len as u64) {
+            Some(remaining) => {
+                self.remaining = remaining;
+                Ok(len)
+            }
+            None => {
+                self.exceeded.set(true);
+                Err(io::Error::other("went past the max crate size"))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CrateTooLarge {
+    max_size: u64,
+}
+
+impl fmt::Display for CrateTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the crate is over the max crate size of {}",
+            HumanBytes(self.max_size)
+        )
+    }
+}
+
+impl std::error::Error for CrateTooLarge {}
+
+fn extract_crate(
+    download: &Download,
+    crate_path: &Path,
+    dest: &Path,
+    globs: &SnapshotGlobs,
+    dedup: Option<&Dedup>,
+) -> Result<Extraction> {
+    let extraction = extract_from(File::open(crate_path)?, dest, globs, dedup)
+        .inspect_err(|_| discard_extraction(&download.version, dest))?;
+    mark_extracted(download, dest)?;
+    Ok(extraction)
+}
+
+// Extracts the `.crate` streaming in from `reader`, which only counts once all of it matches
+// `version`'s checksum. Whatever got written out from a corrupt, truncated, or cut off download is
+// removed again, so nothing unverified is left behind in `dest`
+fn extract_verified(
+    reader: impl Read,
+    copy: Option<File>,
+    version: &VersionExt,
+    dest: &Path,
+    globs: &SnapshotGlobs,
+    dedup: Option<&Dedup>,
+    max_size: u64,
+) -> Result<Extraction> {
+    let exceeded = Cell::new(false);
+    let reader = SizeLimit {
+        inner: reader,
+        remaining: max_size,
+        exceeded: &exceeded,
+    };
+    let mut reader = HashingReader::new(reader, copy);
+    let result = extract_from(&mut reader, dest, globs, dedup)
+        .and_then(|extraction| Ok((extraction, reader.finish()?)));
+
+    match result {
+        Ok((extraction, sum)) if sum == *version.checksum() => Ok(extraction),
+        Ok(_) => {
+            discard_extraction(version, dest);
+            anyhow::bail!("Checksum mismatch for {}", version.dir_name());
+        }
+        Err(e) => {
+            discard_extraction(version, dest);
+            if exceeded.get() {
+                Err(CrateTooLarge { max_size }.into())
+            } else {
+                Err(e)
+            }
+        }
+    }
+}
+
+// The crate dir can't be trusted after a failed extraction. Nothing to do when it never got created
+fn discard_extraction(version: &VersionExt, dest: &Path) {
+    let crate_dir = dest.join(version.dir_name());
+    if let Err(e) = fs::remove_dir_all(&crate_dir) {
+        if e.kind() != io::ErrorKind::NotFound {
+            tracing::warn!(dir = %crate_dir.display(), error = %e, "couldn't discard the extraction");
+        }
+    }
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+// `.crate`s are always gzipped as of now, but mirrors are free to recompress them. Going off of the
+// magic bytes gives a clear error instead of a confusing one from deep within the tar parsing
+fn decompressor<'a>(reader: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+    let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+    let mut reader = reader;
+    (&mut reader)
+        .take(ZSTD_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    // The peeked bytes still have to go through for the hashing to see them
+    let reader = io::Cursor::new(magic.clone()).chain(reader);
+
+    if magic.starts_with(GZIP_MAGIC) {
+        Ok(Box::new(GzDecoder::new(BufReader::new(reader))))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        #[cfg(feature = "zstd")]
+        return Ok(Box::new(zstd::Decoder::new(reader)?));
+        #[cfg(not(feature = "zstd"))]
+        anyhow::bail!("The crate is zstd compressed, which needs building with the `zstd` feature");
+    } else {
+        anyhow::bail!(
+            "The crate doesn't look like a gzip or zstd archive (starts with {:02x?})",
+            magic
+        );
+    }
+}
+
+fn extract_from(
+    reader: impl Read,
+    dest: &Path,
+    globs: &SnapshotGlobs,
+    dedup: Option<&Dedup>,
+) -> Result<Extraction> {
+    let mut archive = Archive::new(decompressor(reader)?);
+    // Entries are prefixed with `cratename-version/` which is kept when unpacking
+    extract_snapshots(&mut archive, dest, globs, dedup)
+}
+
+// Marks the extraction as finished. The dir won't exist yet if there weren't any snapshots
+fn mark_extracted(download: &Download, dest: &Path) -> Result<()> {
+    let crate_dir = dest.join(download.version.dir_name());
+    interrupt::complete(&crate_dir, || {
+        fs::create_dir_all(&crate_dir)?;
+        File::create(crate_dir.join(EXTRACTED_OK_FILE))?;
+        Ok(())
+    })
+}
+
+// The crates.io crawling policy asks for at most one request per second
+pub const MIN_REQUEST_DELAY_MS: u64 = 1_000;
+
+// Whether downloads from the `dl` pattern in an index's config hit crates.io, and so fall under
+// its crawling policy
+pub fn is_crates_io(dl: &str) -> bool {
+    let host = dl
+        .split_once("://")
+        .map_or(dl, |(_, rest)| rest)
+        .split(['/', ':'])
+        .next()
+        .unwrap_or_default();
+    host == "crates.io" || host.ends_with(".crates.io")
+}
+
+// Spaces out the start of requests across all of the download workers, so that several downloads
+// can be in flight while still respecting the crawling policy
+struct RateLimiter {
+    interval: Duration,
+    next_start: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_start: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Blocks until the caller is allowed to start its request
+    fn wait(&self) {
+        let start = {
+            let mut next_start = self.next_start.lock().unwrap();
+            let start = (*next_start).max(Instant::now());
+            *next_start = start + self.interval;
+            start
+        };
+        sleep(start.saturating_duration_since(Instant::now()));
+    }
+}
+
+// Options that control how crates get downloaded and extracted
+pub struct DownloadOpts {
+    // Where the snapshots get extracted to
+    pub out_dir: PathBuf,
+    pub retries: usize,
+    pub request_delay: Duration,
+    pub user_agent: String,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    // How many crates get downloaded and extracted at once
+    pub jobs: usize,
+    // Where `.crate`s are reused from and kept in. Usually cargo's cache
+    pub crate_dir: PathBuf,
+    // Whether `.crate`s are kept in the `crate_dir`. Otherwise downloads never touch the disk and
+    // reused cached copies get removed once they're extracted
+    pub keep_crate: bool,
+    // Where to write a JSON lines listing of every extracted snapshot
+    pub manifest: Option<PathBuf>,
+    // Skips writing snapshots that are identical to one that was already extracted
+    pub dedup: bool,
+    // Stops pulling any more crates after the first one that fails
+    pub fail_fast: bool,
+    pub bar_style: BarStyle,
+    // Which files in each crate get extracted
+    pub snapshot_globs: SnapshotGlobs,
+    // Only keeps the snapshots with a line that matches
+    pub grep: Option<BytesRegex>,
+    // Where every snapshot gets copied to under a flat name
+    pub collect_dir: Option<PathBuf>,
+    // Records each crate once it's fully pulled
+    pub ledger: Option<Ledger>,
+    // Prints a `CrateEvent` to stdout as each crate finishes
+    pub json_lines: bool,
+    // No new crates get started past this, but the ones in flight still finish
+    pub deadline: Option<Instant>,
+    // Remembers what each extracted crate's snapshots were for later runs
+    pub listings: Listings,
+    // Downloads get cut off once they go past this many bytes
+    pub max_crate_size: Option<u64>,
+    #[cfg(feature = "sqlite")]
+    pub sqlite: Option<sqlite::Export>,
+}
+
+// How the download bar gets drawn
+#[derive(Debug, Clone)]
+pub enum BarStyle {
+    Default,
+    // Sticks to ASCII for terminals that mangle the block characters
+    Plain,
+    // Leaves out the bar to make more room for the bytes received by each download
+    Bytes,
+    // A raw `indicatif` template. Should be checked with `validate_template()` first
+    Template(String),
+}
+
+// The keys that `indicatif` knows how to fill in
+const TEMPLATE_KEYS: &[&str] = &[
+    "bar",
+    "wide_bar",
+    "spinner",
+    "prefix",
+    "msg",
+    "wide_msg",
+    "pos",
+    "len",
+    "bytes",
+    "percent",
+    "total_bytes",
+    "elapsed_precise",
+    "elapsed",
+    "per_sec",
+    "bytes_per_sec",
+    "binary_bytes_per_sec",
+    "eta_precise",
+    "eta",
+    "duration_precise",
+    "duration",
+];
+
+// `indicatif` silently renders anything it doesn't understand as empty, so this catches typos up
+// front instead
+pub fn validate_template(template: &str) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let after = &rest[start + 1..];
+        let end = after
+            .find(['{', '}'])
+            .filter(|&end| after[end..].starts_with('}'))
+            .ok_or_else(|| format!("unclosed `{{` at {}", template.len() - rest.len() + start))?;
+        let key = after[..end].split(':').next().unwrap_or_default();
+        if !TEMPLATE_KEYS.contains(&key) {
+            return Err(format!("unknown template key `{}`", key));
+        }
+        rest = &after[end + 1..];
+    }
+
+    if rest.contains('}') {
+        Err("unmatched `}`".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+impl BarStyle {
+    fn progress_style(&self) -> ProgressStyle {
+        let counter = format!(
+            "{}{{pos:.cyan.bold}}{}{{len:.cyan.bold}}{}",
+            "(".cyan().bold(),
+            "/".cyan().bold(),
+            ")".cyan().bold(),
+        );
+        let eta = format!("{} {{eta:<3.green.bold}}", "eta".green().bold());
+        let with_bar = format!(
+            "{} {} {}{{bar:60.blue}}{} {} {{wide_msg}}",
+            "Downloading:".bold(),
+            counter,
+            "|".bold(),
+            "|".bold(),
+            eta,
+        );
+        let style = ProgressStyle::default_bar();
+        match self {
+            Self::Default => style.template(&with_bar).progress_chars("█▉▊▋▌▍▎▏ "),
+            Self::Plain => style.template(&with_bar).progress_chars("=> "),
+            Self::Bytes => style.template(&format!(
+                "{} {} {} {{wide_msg}}",
+                "Downloading:".bold(),
+                counter,
+                eta
+            )),
+            Self::Template(template) => style.template(template),
+        }
+    }
+}
+
+// What a run got up to from start to finish
+#[derive(Debug, Default, Serialize)]
+pub struct RunSummary {
+    // How many crates the index scan went through. Unknown when reusing a scan that didn't record it
+    pub scanned: Option<u64>,
+    pub matched: usize,
+    pub already_local: usize,
+    pub downloaded: usize,
+    // How many of the downloaded crates were extracted from the crate dir instead of the network
+    pub from_cache: usize,
+    pub failed: usize,
+    pub snapshots_found: usize,
+    // How many of the crates turned out to not have any snapshots
+    pub snapshotless: usize,
+    // How many lines in the found snapshots matched `--grep` when it was passed
+    pub grep_matches: Option<usize>,
+    // How many crates were skipped for being over `--max-crate-size`
+    pub too_large: usize,
+    // Whether pulling stopped early from running past `--timeout-total`
+    pub timed_out: bool,
+}
+
+impl RunSummary {
+    pub fn report(&self) -> Result<()> {
+        let mut template = String::new();
+        let mut disps = Vec::new();
+        if let Some(scanned) = self.scanned {
+            template.push_str("Scanned {} crates, ");
+            disps.extend(disps![scanned as usize]);
+        }
+        template.push_str(
+            "matched {} ({} already local), pulled {} ({} from cache), {} failed, found {} \
+             snapshots ({} crates had none)",
+        );
+        disps.extend(disps![
+            self.matched,
+            self.already_local,
+            self.downloaded,
+            self.from_cache,
+            self.failed,
+            self.snapshots_found,
+            self.snapshotless
+        ]);
+        if let Some(grep_matches) = self.grep_matches {
+            template.push_str(" with {} matching lines");
+            disps.extend(disps![grep_matches]);
+        }
+        if self.too_large != 0 {
+            template.push_str(", skipped {} over the max crate size");
+            disps.extend(disps![self.too_large]);
+        }
+        if self.timed_out {
+            template.push_str(", stopped early at the time limit");
+        }
+        Dialog::summary_with(&template, disps)?;
+
+        Ok(())
+    }
+}
+
+// Returns the summary with just the parts about pulling the crates filled in. The `local` crates
+// aren't pulled again, but their snapshots are still reported
+pub fn download_crates(
+    downloads: &[Download],
+    mut local: Vec<LocalCrate>,
+    opts: &DownloadOpts,
+) -> Result<RunSummary> {
+    let pb = new_progress_bar(downloads.len() as u64).with_style(opts.bar_style.progress_style());
+    let full_dl_dialog = Dialog::new("Downloading crates...")?;
+    let agent = ureq::builder()
+        // Setting a description user agent per crates.io crawling policy
+        .user_agent(&opts.user_agent)
+        .timeout_connect(opts.connect_timeout)
+        .timeout_read(opts.read_timeout)
+        .build();
+    let puller = Puller {
+        agent,
+        limiter: RateLimiter::new(opts.request_delay),
+        dest: opts.out_dir.clone(),
+        opts,
+        dedup: opts.dedup.then(Dedup::default),
+        dialog: full_dl_dialog.with_progress_bar(pb.clone()),
+        pb,
+        transfers: Transfers::default(),
+        msgs: PullMsgs::new()?,
+        num_from_cache: AtomicUsize::new(0),
+    };
+    if opts.json_lines {
+        for local in &local {
+            emit_event(&local.version, CrateStatus::Local, local.snapshots.len())?;
+        }
+    }
+    let num_install_errors = AtomicUsize::new(0);
+    let num_too_large = AtomicUsize::new(0);
+    let stopped = AtomicBool::new(false);
+    let timed_out = AtomicBool::new(false);
+    let pulled = Mutex::new(Vec::new());
+    let pool = ThreadPoolBuilder::new().num_threads(opts.jobs).build()?;
+    let result = pool.install(|| {
+        downloads.par_iter().try_for_each(|download| {
+            // The pool keeps handing out the rest, so they just get skipped
+            if stopped.load(atomic::Ordering::Relaxed) {
+                return Ok(());
+            }
+            if opts
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                timed_out.store(true, atomic::Ordering::Relaxed);
+                return Ok(());
+            }
+
+            puller.pull(download).and_then(|outcome| {
+                match outcome {
+                    PullOutcome::Extracted(snapshots) => {
+                        if let Some(ledger) = &opts.ledger {
+                            ledger.record(&download.version.dir_name())?;
+                        }
+                        pulled.lock().unwrap().push((download, snapshots));
+                    }
+                    PullOutcome::TooLarge => {
+                        num_too_large.fetch_add(1, atomic::Ordering::Relaxed);
+                    }
+                    PullOutcome::Failed => {
+                        num_install_errors.fetch_add(1, atomic::Ordering::Relaxed);
+                        if opts.fail_fast {
+                            stopped.store(true, atomic::Ordering::Relaxed);
+                        }
+                    }
+                }
+                puller.pb.inc(1);
+                Ok(())
+            })
+        })
+    });
+    puller.pb.finish();
+
+    // Whatever did get pulled still makes it into the manifest
+    let mut pulled = pulled.into_inner().unwrap();
+    // Checked before grepping since that also leaves crates without snapshots
+    let snapshotless: Vec<_> = pulled
+        .iter()
+        .map(|(download, snapshots)| (&download.version, snapshots))
+        .chain(local.iter().map(|local| (&local.version, &local.snapshots)))
+        .filter(|(_, snapshots)| snapshots.is_empty())
+        .map(|(version, _)| version.dir_name())
+        .collect();
+    for dir_name in &snapshotless {
+        puller
+            .dialog
+            .msg_with(Color::BrightBlack, "{}: no snapshots", disps![dir_name])?;
+    }
+    record_snapshotless(&snapshotless)?;
+    let grep_matches = match &opts.grep {
+        Some(grep) => {
+            let grep_dialog = puller
+                .dialog
+                .info_with("Searching the snapshots for {}", disps![grep.as_str()])?;
+            let mut num_matches = 0;
+            for (download, snapshots) in &mut pulled {
+                let crate_dir = puller.dest.join(download.version.dir_name());
+                num_matches += grep_snapshots(
+                    &grep_dialog,
+                    &download.version,
+                    &crate_dir,
+                    &puller.dest,
+                    snapshots,
+                    grep,
+                )?;
+            }
+            for local in &mut local {
+                num_matches += grep_snapshots(
+                    &grep_dialog,
+                    &local.version,
+                    &local.dir,
+                    &local.dir,
+                    &mut local.snapshots,
+                    grep,
+                )?;
+            }
+            Some(num_matches)
+        }
+        None => None,
+    };
+    if let Some(collect_dir) = &opts.collect_dir {
+        let pulled = pulled.iter().map(|(download, snapshots)| {
+            let crate_dir = puller.dest.join(download.version.dir_name());
+            (&download.version, crate_dir, snapshots.as_slice())
+        });
+        let local = local.iter().map(|local| {
+            (
+                &local.version,
+                local.dir.clone(),
+                local.snapshots.as_slice(),
+            )
+        });
+        let num_collected = collect_snapshots(collect_dir, &puller.dest, pulled.chain(local))?;
+        puller.dialog.info_with(
+            "Collected {} snapshots into {}",
+            disps![num_collected, collect_dir],
+        )?;
+    }
+    if let Some(manifest) = &opts.manifest {
+        pulled.sort_by(|(a, _), (b, _)| a.url.cmp(&b.url));
+        let pulled = pulled
+            .iter()
+            .map(|(download, snapshots)| (&download.version, snapshots.as_slice()));
+        let local = local
+            .iter()
+            .map(|local| (&local.version, local.snapshots.as_slice()));
+        write_manifest(manifest, pulled.chain(local))?;
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(export) = &opts.sqlite {
+        let pulled = pulled.iter().map(|(download, snapshots)| {
+            let crate_dir = puller.dest.join(download.version.dir_name());
+            (
+                &download.version,
+                crate_dir,
+                puller.dest.as_path(),
+                snapshots.as_slice(),
+            )
+        });
+        let local = local.iter().map(|local| {
+            (
+                &local.version,
+                local.dir.clone(),
+                local.dir.as_path(),
+                local.snapshots.as_slice(),
+            )
+        });
+        let num_exported = export.write(pulled.chain(local))?;
+        puller.dialog.info_with(
+            "Exported {} crates into {}",
+            disps![num_exported, export.path.as_path()],
+        )?;
+    }
+    if puller.dedup.is_some() {
+        let num_duplicates = write_duplicates(&puller.dest, &pulled)?;
+        puller.dialog.info_with(
+            "Elided {} duplicate snapshots (see {})",
+            disps![num_duplicates, puller.dest.join(DUPLICATES_FILE)],
+        )?;
+    }
+    result?;
+
+    let all_snapshots = || {
+        let local = local.iter().map(|local| &local.snapshots);
+        pulled.iter().map(|(_, snapshots)| snapshots).chain(local)
+    };
+    let num_snapshots: usize = all_snapshots().map(Vec::len).sum();
+    let num_crates = all_snapshots()
+        .filter(|snapshots| !snapshots.is_empty())
+        .count();
+    puller.dialog.info_with(
+        "Collected {} snapshots across {} crates",
+        disps![num_snapshots, num_crates],
+    )?;
+
+    let num_install_errors = num_install_errors.into_inner();
+    if num_install_errors != 0 {
+        puller
+            .dialog
+            .warn_with("Failed pulling {} crates", disps![num_install_errors])?;
+    }
+    if stopped.into_inner() {
+        puller
+            .dialog
+            .warn("Stopped early since failing on errors was requested")?;
+    }
+    let timed_out = timed_out.into_inner();
+    if timed_out {
+        // Crates that got pulled are local (and in the ledger) now, so they get skipped next time
+        puller.dialog.warn(
+            "Stopped early since the time limit ran out. Running again picks up where this left off",
+        )?;
+    }
+
+    Ok(RunSummary {
+        downloaded: pulled.len(),
+        from_cache: puller.num_from_cache.into_inner(),
+        failed: num_install_errors,
+        snapshots_found: num_snapshots,
+        snapshotless: snapshotless.len(),
+        grep_matches,
+        too_large: num_too_large.into_inner(),
+        timed_out,
+        ..RunSummary::default()
+    })
+}
+
+// Remembered so that later runs can skip them with `--skip-snapshotless`
+fn record_snapshotless(dir_names: &[String]) -> Result<()> {
+    if dir_names.is_empty() {
+        return Ok(());
+    }
+
+    let mut state = State::load()?;
+    state.snapshotless.extend(dir_names.iter().cloned());
+    if let Err(e) = state.save() {
+        Dialog::raw_with_indent(NonZeroUsize::new(1).unwrap())
+            .warn_with("Failed saving the crates without snapshots: {}", disps![e])?;
+    }
+
+    Ok(())
+}
+
+// Only keeps the `snapshots` that have a line matching `grep`, displaying each one along the way.
+// Deduped snapshots are read from the original copy in `dest`. Returns how many lines matched
+fn grep_snapshots(
+    dialog: &Dialog,
+    version: &VersionExt,
+    crate_dir: &Path,
+    dest: &Path,
+    snapshots: &mut Vec<ExtractedSnapshot>,
+    grep: &BytesRegex,
+) -> Result<usize> {
+    let mut num_matches = 0;
+    let mut kept = Vec::new();
+    for snapshot in snapshots.drain(..) {
+        let path = snapshot.location(crate_dir, dest);
+        match matching_lines(&path, grep) {
+            Ok(0) => {}
+            Ok(num_lines) => {
+                dialog.info_with(
+                    "{} {}: {} matching lines",
+                    disps![version, snapshot.path.as_path(), num_lines],
+                )?;
+                num_matches += num_lines;
+                kept.push(snapshot);
+            }
+            Err(e) => {
+                dialog.warn_with(
+                    "Failed searching {}: {}",
+                    disps![path, anyhow::Error::from(e)],
+                )?;
+            }
+        }
+    }
+    *snapshots = kept;
+
+    Ok(num_matches)
+}
+
+// Reads a line at a time so that huge snapshots don't have to fit in memory
+fn matching_lines(path: &Path, grep: &BytesRegex) -> io::Result<usize> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut line = Vec::new();
+    let mut num_matches = 0;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if grep.is_match(&line) {
+            num_matches += 1;
+        }
+    }
+
+    Ok(num_matches)
+}
+
+// Everything that's shared between the download workers
+struct Puller<'a> {
+    agent: Agent,
+    limiter: RateLimiter,
+    dest: PathBuf,
+    opts: &'a DownloadOpts,
+    dedup: Option<Dedup>,
+    dialog: Dialog,
+    pb: ProgressBar,
+    transfers: Transfers,
+    msgs: PullMsgs,
+    num_from_cache: AtomicUsize,
+}
+
+// How pulling a single crate went
+enum PullOutcome {
+    Extracted(Vec<ExtractedSnapshot>),
+    Failed,
+    // Went past `--max-crate-size` while downloading
+    TooLarge,
+}
+
+// Where a crate gets pulled from. The cache is tried first so that the network is a last resort.
+// Crates that are already extracted get sorted out by `get_uninstalled()` before either
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CrateSource {
+    Cache,
+    Network,
+}
+
+// These get displayed for every crate, so they're only parsed once
+struct PullMsgs {
+    cached: FmtStr,
+    bad_cache: FmtStr,
+    downloading: FmtStr,
+    extracted: FmtStr,
+    failed: FmtStr,
+}
+
+impl PullMsgs {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            cached: FmtStr::parse("Using cached {}")?,
+            bad_cache: FmtStr::parse("Cached {} doesn't match its checksum, downloading it again")?,
+            downloading: FmtStr::parse("Downloading {}...")?,
+            extracted: FmtStr::parse("Extracted {} ({} snapshots)")?,
+            failed: FmtStr::parse("Failed pulling {}, Err: {}")?,
+        })
+    }
+}
+
+impl Puller<'_> {
+    // Extracts straight from the response instead of round-tripping through a `.crate` on disk.
+    // The `.crate` only gets saved to `crate_path` when keeping crates. It's written to a `.part`
+    // file first and only renamed once it's verified, so any `.crate` in the cache is complete
+    fn download(
+        &self,
+        download: &Download,
+        crate_path: &Path,
+        dialog: &Dialog,
+    ) -> Result<Extraction> {
+        let resp = call_with_retries(
+            &self.agent,
+            &self.limiter,
+            &download.url,
+            self.opts.retries,
+            dialog,
+        )?;
+        let total: Option<u64> = resp
+            .header("Content-Length")
+            .and_then(|len| len.parse().ok());
+        let max_size = self.opts.max_crate_size.unwrap_or(u64::MAX);
+        // No point in downloading any of it when the server already says it's too big
+        if total.is_some_and(|total| total > max_size) {
+            return Err(CrateTooLarge { max_size }.into());
+        }
+        let keep_crate = self.opts.keep_crate;
+        let part_path = crate_path.with_extension("crate.part");
+        let _writing_crate = keep_crate.then(|| InProgress::new(part_path.clone()));
+        let copy = if keep_crate {
+            Some(File::create(&part_path)?)
+        } else {
+            None
+        };
+        let resp = TransferReader::new(
+            resp.into_reader(),
+            download.file_name(),
+            total,
+            &self.transfers,
+            &self.pb,
+        );
+        let result = extract_verified(
+            resp,
+            copy,
+            &download.version,
+            &self.dest,
+            &self.opts.snapshot_globs,
+            self.dedup.as_ref(),
+            max_size,
+        );
+
+        match result {
+            Ok(extraction) => {
+                mark_extracted(download, &self.dest)?;
+                // The snapshots are already extracted, so losing the `.crate` isn't worth failing
+                // the crate over
+                if keep_crate {
+                    if let Err(e) = fs::rename(&part_path, crate_path) {
+                        dialog.warn_with(
+                            "Failed saving {}: {}",
+                            disps![crate_path, anyhow::Error::from(e)],
+                        )?;
+                        let _ = fs::remove_file(&part_path);
+                    }
+                }
+                Ok(extraction)
+            }
+            Err(e) => {
+                if keep_crate {
+                    let _ = fs::remove_file(&part_path);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Downloads (or reuses the cached copy of) a single crate and extracts it
+    fn pull(&self, download: &Download) -> Result<PullOutcome> {
+        let url = &download.url;
+        let file_name = download.file_name();
+        let cache_path = self.opts.crate_dir.join(&file_name);
+        // A complete extraction from an earlier run shouldn't get cleaned up if we're interrupted
+        let crate_dir = self.dest.join(download.version.dir_name());
+        let _extracting = (!is_fully_extracted(&crate_dir)).then(|| InProgress::new(crate_dir));
+
+        // No need to hit the network if we already have a good copy
+        let source = if !cache_path.exists() {
+            CrateSource::Network
+        } else if download.is_valid_at(&cache_path) {
+            CrateSource::Cache
+        } else {
+            self.dialog
+                .warn_fmt(&self.msgs.bad_cache, disps![&file_name])?;
+            CrateSource::Network
+        };
+
+        let (crate_dl_dialog, result) = if source == CrateSource::Cache {
+            let crate_dl_dialog =
+                self.dialog
+                    .msg_fmt(Color::Cyan, &self.msgs.cached, disps![&file_name])?;
+            let result = extract_crate(
+                download,
+                &cache_path,
+                &self.dest,
+                &self.opts.snapshot_globs,
+                self.dedup.as_ref(),
+            );
+            if result.is_ok() && !self.opts.keep_crate {
+                // Extraction already worked, so failing here isn't worth failing the crate over
+                if let Err(e) = fs::remove_file(&cache_path) {
+                    crate_dl_dialog.warn_with(
+                        "Failed removing {}: {}",
+                        disps![cache_path.as_path(), anyhow::Error::from(e)],
+                    )?;
+                }
+            }
+            (crate_dl_dialog, result)
+        } else {
+            let crate_dl_dialog = self.dialog.info_fmt(&self.msgs.downloading, disps![url])?;
+            // Performing at most one request per second per crates.io crawling policy
+            let result = self.download(download, &cache_path, &crate_dl_dialog);
+            (crate_dl_dialog, result)
+        };
+
+        match result {
+            Ok(Extraction {
+                snapshots,
+                rejected,
+                written,
+            }) => {
+                if let Some(dedup) = &self.dedup {
+                    dedup.commit(written);
+                }
+                for path in rejected {
+                    crate_dl_dialog.warn_with(
+                        "Refused extracting {} since it could escape the out dir",
+                        disps![path],
+                    )?;
+                }
+                crate_dl_dialog.msg_fmt(
+                    Color::Green,
+                    &self.msgs.extracted,
+                    disps![file_name, snapshots.len()],
+                )?;
+                // Deduped snapshots aren't on disk, so this lists what a later run would find
+                relist_snapshots(
+                    &self.dest.join(download.version.dir_name()),
+                    download.version.checksum(),
+                    &self.opts.snapshot_globs,
+                    &self.opts.listings,
+                );
+                if source == CrateSource::Cache {
+                    self.num_from_cache.fetch_add(1, atomic::Ordering::Relaxed);
+                }
+                if self.opts.json_lines {
+                    let status = match source {
+                        CrateSource::Cache => CrateStatus::Cached,
+                        CrateSource::Network => CrateStatus::Downloaded,
+                    };
+                    emit_event(&download.version, status, snapshots.len())?;
+                }
+                Ok(PullOutcome::Extracted(snapshots))
+            }
+            Err(e) if e.is::<CrateTooLarge>() => {
+                crate_dl_dialog.warn_with("Skipped {} since {}", disps![file_name, e])?;
+                if self.opts.json_lines {
+                    emit_event(&download.version, CrateStatus::TooLarge, 0)?;
+                }
+                Ok(PullOutcome::TooLarge)
+            }
+            Err(e) => {
+                crate_dl_dialog.warn_fmt(&self.msgs.failed, disps![url, e])?;
+                if self.opts.json_lines {
+                    emit_event(&download.version, CrateStatus::Failed, 0)?;
+                }
+                Ok(PullOutcome::Failed)
+            }
+        }
+    }
+}
+
+// Downloads and extracts the snapshots for all of `versions` from `index`'s registry
+pub fn download_and_extract(
+    index: &Index,
+    versions: Vec<VersionExt>,
+    opts: &DownloadOpts,
+) -> Result<RunSummary> {
+    let config = index.index_config()?;
+    let downloads: Vec<_> = versions
+        .into_iter()
+        .filter_map(|version| {
+            let url = version.download_url(&config)?;
+            Some(Download { version, url })
+        })
+        .collect();
+    download_crates(&downloads, Vec::new(), opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Index entries for a crate that renames `insta` and one that renames something else to `insta`
+    const RENAMED_DEPS_FIXTURE: &str = r#"
+{"name":"renames-insta","vers":"0.1.0","deps":[{"name":"snap","req":"^1","features":[],"package":"insta","kind":"dev","target":null,"optional":false,"default_features":true}],"cksum":"0000000000000000000000000000000000000000000000000000000000000000","features":{},"yanked":false}
+{"name":"fake-insta","vers":"0.1.0","deps":[{"name":"insta","req":"^1","features":[],"package":"other","kind":"normal","target":null,"optional":false,"default_features":true}],"cksum":"0000000000000000000000000000000000000000000000000000000000000000","features":{},"yanked":false}
+"#;
+
+    // An index entry with an all zeros checksum, so no real tarball matches it. Each of `deps` is a
+    // `^1` dev dependency
+    pub(crate) fn index_entry(name: &str, vers: &str, deps: &[&str], yanked: bool) -> VersionExt {
+        let deps: Vec<_> = deps
+            .iter()
+            .map(|dep| {
+                format!(
+                    r#"{{"name":"{}","req":"^1","features":[],"kind":"dev","target":null,"optional":false,"default_features":true}}"#,
+                    dep
+                )
+            })
+            .collect();
+        let line = format!(
+            r#"{{"name":"{}","vers":"{}","deps":[{}],"cksum":"{}","features":{{}},"yanked":{}}}"#,
+            name,
+            vers,
+            deps.join(","),
+            "0".repeat(64),
+            yanked
+        );
+        VersionExt::new(serde_json::from_str(&line).unwrap())
+    }
+
+    fn fixture_versions() -> Vec<VersionExt> {
+        RENAMED_DEPS_FIXTURE
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| VersionExt::new(serde_json::from_str(line).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn highest_version_skips_yanked_and_pre() {
+        let versions: Vec<Version> = [("0.1.0", false), ("0.2.0", true), ("0.3.0-beta.1", false)]
+            .iter()
+            .map(|(vers, yanked)| index_entry("foo", vers, &[], *yanked).inner().to_owned())
+            .collect();
+        let mut filter = DepFilter::any_kind("insta");
+        let highest = |filter: &DepFilter| {
+            find_highest_version(&versions, filter).map(|version| version.version().to_owned())
+        };
+
+        assert_eq!(highest(&filter).as_deref(), Some("0.1.0"));
+        filter.include_pre = true;
+        assert_eq!(highest(&filter).as_deref(), Some("0.3.0-beta.1"));
+        filter.yank_policy = YankPolicy::Only;
+        assert_eq!(highest(&filter).as_deref(), Some("0.2.0"));
+    }
+
+    #[test]
+    fn renamed_deps() {
+        let filter = DepFilter::any_kind("insta");
+        let versions = fixture_versions();
+        let (renames_insta, fake_insta) = (&versions[0], &versions[1]);
+        assert_eq!(filter.uses_any(renames_insta), Ok(true));
+        assert_eq!(filter.uses_any(fake_insta), Ok(false));
+        assert_eq!(DepFilter::any_kind("other").uses_any(fake_insta), Ok(true));
+
+        let requirements = RequirementRecord::all(renames_insta, &filter);
+        let requirements: Vec<_> = requirements
+            .iter()
+            .map(|req| (req.dep, req.requirement, req.kind))
+            .collect();
+        assert_eq!(requirements, [("insta", "^1", "dev")]);
+    }
+
+    #[test]
+    fn version_selection() {
+        let version = |vers: &str, yanked: bool| -> Version {
+            index_entry("foo", vers, &[], yanked).inner().to_owned()
+        };
+        let versions = [
+            version("0.1.0", false),
+            version("0.3.0", true),
+            version("0.2.0", false),
+            version("0.4.0-beta.1", false),
+        ];
+        let filter = |yank_policy, include_pre| DepFilter {
+            yank_policy,
+            include_pre,
+            ..DepFilter::any_kind("insta")
+        };
+        let select = |filter: DepFilter, select| -> Vec<String> {
+            filter
+                .select_versions(&versions, select)
+                .iter()
+                .map(|version| version.version().to_owned())
+                .collect()
+        };
+
+        assert_eq!(
+            select(filter(YankPolicy::Skip, false), VersionSelect::Highest),
+            ["0.2.0"]
+        );
+        assert_eq!(
+            select(filter(YankPolicy::Include, false), VersionSelect::Highest),
+            ["0.3.0"]
+        );
+        assert_eq!(
+            select(filter(YankPolicy::Only, false), VersionSelect::Highest),
+            ["0.3.0"]
+        );
+        assert_eq!(
+            select(filter(YankPolicy::Skip, false), VersionSelect::All),
+            ["0.1.0", "0.2.0"]
+        );
+        assert_eq!(
+            select(filter(YankPolicy::Only, false), VersionSelect::All),
+            ["0.3.0"]
+        );
+        assert_eq!(
+            select(filter(YankPolicy::Skip, true), VersionSelect::Highest),
+            ["0.4.0-beta.1"]
+        );
+        let all_yanked = [version("0.1.0", true)];
+        assert!(filter(YankPolicy::Skip, false)
+            .select_versions(&all_yanked, VersionSelect::Highest)
+            .is_empty());
+    }
+
+    #[test]
+    fn lowest_matching_version() {
+        let version = |vers: &str, deps: &[&str]| -> Version {
+            index_entry("foo", vers, deps, false).inner().to_owned()
+        };
+        let versions = [
+            version("0.3.0", &["insta"]),
+            version("0.1.0", &[]),
+            version("0.2.0", &["insta"]),
+        ];
+        let filter = DepFilter::any_kind("insta");
+        let matching = |select| -> Vec<String> {
+            filter
+                .matching_versions(&versions, select)
+                .into_iter()
+                .map(|version| version.ok().unwrap().version().to_owned())
+                .collect()
+        };
+
+        assert_eq!(matching(VersionSelect::Highest), ["0.3.0"]);
+        assert_eq!(matching(VersionSelect::Lowest), ["0.2.0"]);
+        assert_eq!(matching(VersionSelect::All), ["0.2.0", "0.3.0"]);
+    }
+
+    #[test]
+    fn progress_templates() {
+        assert_eq!(
+            validate_template("{pos}/{len} {bar:40.blue} {wide_msg}"),
+            Ok(())
+        );
+        assert_eq!(validate_template("no keys at all"), Ok(()));
+        assert!(validate_template("{pso}").is_err());
+        assert!(validate_template("{pos").is_err());
+        assert!(validate_template("{pos {len}").is_err());
+        assert!(validate_template("pos}").is_err());
+    }
+
+    #[test]
+    fn dep_stats() {
+        let filter = DepFilter::any_kind("insta");
+        let versions = fixture_versions();
+        let stats = DepStats::new(&filter, &versions[..1]);
+        assert_eq!(stats.total, 1);
+        assert_eq!(stats.by_kind.get("dev"), Some(&1));
+        assert_eq!(stats.by_kind.get("normal"), None);
+        assert_eq!(stats.requirement_kinds.get("caret"), Some(&1));
+        assert_eq!(stats.top_requirements.len(), 1);
+        assert_eq!(stats.top_requirements[0].requirement, "^1");
+    }
+
+    fn parse(dir_name: &str) -> Option<(&str, String)> {
+        parse_dir_name(dir_name).map(|(name, version)| (name, version.to_string()))
+    }
+
+    #[test]
+    fn dir_names() {
+        assert_eq!(
+            parse("some-crate-0.1.0"),
+            Some(("some-crate", "0.1.0".to_owned()))
+        );
+        assert_eq!(
+            parse("x-1.0.0-alpha.1+build.2"),
+            Some(("x", "1.0.0-alpha.1+build.2".to_owned()))
+        );
+        assert_eq!(parse("1-2-3-0.1.0"), Some(("1-2-3", "0.1.0".to_owned())));
+        assert_eq!(
+            parse("foo-bar-1.0.0-rc.1"),
+            Some(("foo-bar", "1.0.0-rc.1".to_owned()))
+        );
+        // The pre-release looks like another `name-version`, but names can't have `.`s
+        assert_eq!(
+            parse("a-1.0.0-b-2.0.0"),
+            Some(("a", "1.0.0-b-2.0.0".to_owned()))
+        );
+    }
+
+    #[test]
+    fn invalid_dir_names() {
+        assert_eq!(parse("no-version"), None);
+        assert_eq!(parse("-1.0.0"), None);
+        assert_eq!(parse("crate-1.0"), None);
+        assert_eq!(parse("crate-01.0.0"), None);
+    }
+
+    #[test]
+    fn dir_names_round_trip() {
+        for dir_name in ["some-crate-0.1.0", "x-1.0.0-alpha.1+build.2", "1-2-3-0.1.0"] {
+            let (name, version) = parse_dir_name(dir_name).unwrap();
+            assert_eq!(format!("{}-{}", name, version), dir_name);
+        }
+    }
+
+    #[test]
+    fn rate_limiter_spaces_out_starts() {
+        let limiter = RateLimiter::new(Duration::from_millis(50));
+        let start = Instant::now();
+        thread::scope(|scope| {
+            for _ in 0..3 {
+                scope.spawn(|| limiter.wait());
+            }
+        });
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    // A gzipped tarball laid out like a `.crate`
+    fn crate_tarball(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *contents).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    // A fresh dir that gets removed again once it's dropped, even when the test fails
+    pub(crate) struct TempDir(PathBuf);
+
+    impl Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    pub(crate) fn temp_dir(name: &str) -> TempDir {
+        let dir = env::temp_dir().join(format!("dumpsta-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    #[test]
+    fn streams_only_snapshots() {
+        let tarball = crate_tarball(&[
+            ("foo-0.1.0/Cargo.toml", b"[package]"),
+            ("foo-0.1.0/tests/snapshots/a.snap", b"snap"),
+        ]);
+        let dest = temp_dir("streams-only-snapshots");
+
+        let mut reader = HashingReader::new(tarball.as_slice(), None);
+        let snapshots = extract_from(&mut reader, &dest, &SnapshotGlobs::default(), None)
+            .unwrap()
+            .snapshots;
+        let sum = reader.finish().unwrap();
+
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].path, Path::new("tests/snapshots/a.snap"));
+        assert_eq!(snapshots[0].size, 4);
+
+        let expected: [u8; 32] = Sha256::digest(&tarball).into();
+        assert_eq!(sum, expected);
+        assert!(dest.join("foo-0.1.0/tests/snapshots/a.snap").is_file());
+        assert!(!dest.join("foo-0.1.0/Cargo.toml").exists());
+    }
+
+    #[test]
+    fn sniffs_the_compression() {
+        let dest = temp_dir("sniffs-the-compression");
+        let globs = SnapshotGlobs::default();
+        let err = extract_from(b"PK\x03\x04".as_slice(), &dest, &globs, None).unwrap_err();
+        assert!(err.to_string().contains("[50, 4b, 03, 04]"), "{}", err);
+
+        #[cfg(feature = "zstd")]
+        {
+            let tarball = crate_tarball(&[("foo-0.1.0/a.snap", b"snap")]);
+            let tar = flate2::read::GzDecoder::new(tarball.as_slice());
+            let zstd_tarball = zstd::encode_all(tar, 0).unwrap();
+            let snapshots = extract_from(zstd_tarball.as_slice(), &dest, &globs, None)
+                .unwrap()
+                .snapshots;
+            assert_eq!(snapshots.len(), 1);
+        }
+        #[cfg(not(feature = "zstd"))]
+        {
+            let err = extract_from(ZSTD_MAGIC, &dest, &globs, None).unwrap_err();
+            assert!(err.to_string().contains("`zstd` feature"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn stops_past_the_size_limit() {
+        let tarball = crate_tarball(&[("foo-0.1.0/a.snap", b"snap")]);
+        let dest = temp_dir("stops-past-the-size-limit");
+        for (limit, exceeds) in [(tarball.len() as u64, false), (10, true)] {
+            let exceeded = Cell::new(false);
+            let reader = SizeLimit {
+                inner: tarball.as_slice(),
+                remaining: limit,
+                exceeded: &exceeded,
+            };
+            let result = extract_from(reader, &dest, &SnapshotGlobs::default(), None);
+            assert_eq!(result.is_err(), exceeds);
+            assert_eq!(exceeded.get(), exceeds);
+        }
+    }
+
+    #[test]
+    fn refuses_escaping_entries() {
+        let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        // `tar` refuses to write these paths itself, so the name gets set by hand
+        for path in ["foo-0.1.0/../evil.snap", "/abs.snap", "foo-0.1.0/ok.snap"] {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_size(4);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, b"snap".as_slice()).unwrap();
+        }
+        let tarball = builder.into_inner().unwrap().finish().unwrap();
+        let dir = temp_dir("refuses-escaping-entries");
+        let dest = dir.join("out");
+        fs::create_dir_all(&dest).unwrap();
+
+        let extraction =
+            extract_from(tarball.as_slice(), &dest, &SnapshotGlobs::default(), None).unwrap();
+        assert_eq!(extraction.rejected, ["foo-0.1.0/../evil.snap", "/abs.snap"]);
+        assert_eq!(extraction.snapshots.len(), 1);
+        assert!(dest.join("foo-0.1.0/ok.snap").is_file());
+        assert!(!dest.join("evil.snap").exists());
+        assert!(!dir.join("evil.snap").exists());
+    }
+
+    #[test]
+    fn discards_unverified_extractions() {
+        let dest = temp_dir("discards-unverified-extractions");
+        let tarball = crate_tarball(&[("foo-0.1.0/tests/snapshots/a.snap", b"snap")]);
+        let err = extract_verified(
+            tarball.as_slice(),
+            None,
+            &index_entry("foo", "0.1.0", &[], false),
+            &dest,
+            &SnapshotGlobs::default(),
+            None,
+            u64::MAX,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"));
+        assert!(!dest.join("foo-0.1.0").exists());
+    }
+
+    #[test]
+    fn discards_cut_off_extractions() {
+        let dest = temp_dir("discards-cut-off-extractions");
+        // Incompressible, so that the cutoff lands well after the first snapshot was written out
+        let mut state = 1u32;
+        let noise: Vec<u8> = (0..256 * 1024)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect();
+        let tarball = crate_tarball(&[
+            ("foo-0.1.0/tests/snapshots/a.snap", b"snap"),
+            ("foo-0.1.0/tests/snapshots/b.snap", &noise),
+        ]);
+        let err = extract_verified(
+            tarball.as_slice(),
+            None,
+            &index_entry("foo", "0.1.0", &[], false),
+            &dest,
+            &SnapshotGlobs::default(),
+            None,
+            tarball.len() as u64 / 2,
+        )
+        .unwrap_err();
+        assert!(err.is::<CrateTooLarge>());
+        assert!(!dest.join("foo-0.1.0").exists());
+    }
+
+    #[test]
+    fn dedups_identical_snapshots() {
+        let dest = temp_dir("dedups-identical-snapshots");
+        let dedup = Dedup::default();
+        let extract = |name: &str| {
+            let tarball = crate_tarball(&[(name, b"same")]);
+            let extraction = extract_from(
+                tarball.as_slice(),
+                &dest,
+                &SnapshotGlobs::default(),
+                Some(&dedup),
+            )
+            .unwrap();
+            dedup.commit(extraction.written);
+            extraction.snapshots
+        };
+
+        let first = extract("foo-0.1.0/a.snap");
+        let second = extract("bar-0.1.0/b.snap");
+        assert_eq!(first[0].duplicate_of, None);
+        assert_eq!(
+            second[0].duplicate_of.as_deref(),
+            Some(Path::new("foo-0.1.0/a.snap"))
+        );
+        assert!(dest.join("foo-0.1.0/a.snap").is_file());
+        assert!(!dest.join("bar-0.1.0/b.snap").exists());
+    }
+
+    #[test]
+    fn dedups_against_verified_crates_only() {
+        let dest = temp_dir("dedups-against-verified-crates-only");
+        let dedup = Dedup::default();
+        let tarball = crate_tarball(&[("foo-0.1.0/a.snap", b"same")]);
+        extract_verified(
+            tarball.as_slice(),
+            None,
+            &index_entry("foo", "0.1.0", &[], false),
+            &dest,
+            &SnapshotGlobs::default(),
+            Some(&dedup),
+            u64::MAX,
+        )
+        .unwrap_err();
+
+        let tarball = crate_tarball(&[("bar-0.1.0/b.snap", b"same")]);
+        let extraction = extract_from(
+            tarball.as_slice(),
+            &dest,
+            &SnapshotGlobs::default(),
+            Some(&dedup),
+        )
+        .unwrap();
+        assert_eq!(extraction.snapshots[0].duplicate_of, None);
+        assert!(dest.join("bar-0.1.0/b.snap").is_file());
+    }
+
+    #[test]
+    fn keeps_earlier_duplicate_records() {
+        let dest = temp_dir("keeps-earlier-duplicate-records");
+        let earlier = r#"{"path":"old-0.1.0/a.snap","duplicate_of":"older-0.1.0/a.snap"}"#;
+        fs::write(dest.join(DUPLICATES_FILE), format!("{}\n", earlier)).unwrap();
+        let download = Download {
+            version: index_entry("foo", "0.1.0", &[], false),
+            url: String::new(),
+        };
+        let snapshots = vec![ExtractedSnapshot {
+            path: PathBuf::from("b.snap"),
+            size: 4,
+            duplicate_of: Some(PathBuf::from("bar-0.1.0/b.snap")),
+        }];
+        let pulled = [(&download, snapshots)];
+
+        // Pulling the same crate again replaces its records instead of piling them up
+        for _ in 0..2 {
+            assert_eq!(write_duplicates(&dest, &pulled).unwrap(), 1);
+            let index = fs::read_to_string(dest.join(DUPLICATES_FILE)).unwrap();
+            let lines: Vec<_> = index.lines().collect();
+            assert_eq!(
+                lines,
+                [
+                    earlier,
+                    r#"{"path":"foo-0.1.0/b.snap","duplicate_of":"bar-0.1.0/b.snap"}"#
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn finds_stale_crates() {
+        let out_dir = temp_dir("finds-stale-crates");
+        for dir_name in ["foo-1.0.0", "bar-0.1.0", "partial-0.1.0", "skipped-0.1.0"] {
+            fs::create_dir_all(out_dir.join(dir_name)).unwrap();
+        }
+        for dir_name in ["foo-1.0.0", "bar-0.1.0", "skipped-0.1.0"] {
+            fs::write(out_dir.join(dir_name).join(EXTRACTED_OK_FILE), "").unwrap();
+        }
+        fs::write(out_dir.join(DUPLICATES_FILE), "").unwrap();
+
+        // `bar` still uses the deps, just with a newer version now
+        let matched = HashSet::from(["bar"]);
+        let filter = DepFilter {
+            crate_filter: Some(Regex::new("^(foo|bar|partial)$").unwrap()),
+            ..DepFilter::any_kind("insta")
+        };
+        let stale = stale_crates(&out_dir, &filter, &matched).unwrap();
+        assert_eq!(stale, [("foo-1.0.0".to_owned(), out_dir.join("foo-1.0.0"))]);
+    }
+
+    #[test]
+    fn walks_extracted_crates() {
+        let crate_dir = temp_dir("walks-extracted-crates");
+        fs::create_dir_all(crate_dir.join("tests/snapshots")).unwrap();
+        fs::write(crate_dir.join("tests/snapshots/b.snap"), "snap").unwrap();
+        fs::write(crate_dir.join("a.snap.new"), "new").unwrap();
+        fs::write(crate_dir.join("lib.rs"), "").unwrap();
+        fs::write(crate_dir.join(EXTRACTED_OK_FILE), "").unwrap();
+
+        let snapshots: Vec<_> = snapshots_in_crate(&crate_dir, &SnapshotGlobs::default())
+            .into_iter()
+            .map(|snapshot| (snapshot.path, snapshot.size))
+            .collect();
+        assert_eq!(
+            snapshots,
+            [
+                (PathBuf::from("a.snap.new"), 3),
+                (PathBuf::from("tests/snapshots/b.snap"), 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn reuses_unchanged_listings() {
+        let crate_dir = temp_dir("reuses-unchanged-listings");
+        let store = temp_dir("reuses-unchanged-listings-store");
+        let listings = Listings::new(store.to_path_buf());
+        let checksum = [7; 32];
+        let globs = SnapshotGlobs::default();
+        let snap = crate_dir.join("a.snap");
+        fs::write(&snap, "snap").unwrap();
+
+        let sizes = |snapshots: Vec<ExtractedSnapshot>| -> Vec<_> {
+            snapshots
+                .into_iter()
+                .map(|snapshot| snapshot.size)
+                .collect()
+        };
+        assert_eq!(
+            sizes(listed_snapshots(&crate_dir, &checksum, &globs, &listings)),
+            [4]
+        );
+        let listing = listings.load(&checksum).unwrap();
+        assert_eq!(listing.snapshots[0].path, Path::new("a.snap"));
+        assert_eq!(listing.snapshots[0].sha256, hex_sha256(b"snap"));
+
+        // A reused listing doesn't get saved again, so the marked hash sticks around
+        let mut listing = listing;
+        listing.snapshots[0].sha256 = "reused".to_owned();
+        listings.save(&checksum, &listing).unwrap();
+        assert_eq!(
+            sizes(listed_snapshots(&crate_dir, &checksum, &globs, &listings)),
+            [4]
+        );
+        assert_eq!(
+            listings.load(&checksum).unwrap().snapshots[0].sha256,
+            "reused"
+        );
+        // New snapshots throw out the listing
+        fs::write(crate_dir.join("b.snap"), "other").unwrap();
+        assert_eq!(
+            sizes(listed_snapshots(&crate_dir, &checksum, &globs, &listings)),
+            [4, 5]
+        );
+        assert_eq!(
+            listings.load(&checksum).unwrap().snapshots[0].sha256,
+            hex_sha256(b"snap")
+        );
+        // Changing one of them does too
+        fs::write(&snap, "changed").unwrap();
+        assert_eq!(
+            sizes(listed_snapshots(&crate_dir, &checksum, &globs, &listings)),
+            [7, 5]
+        );
+        // As do different globs
+        let only_b = SnapshotGlobs::new(&["b.snap"]).unwrap();
+        assert_eq!(
+            sizes(listed_snapshots(&crate_dir, &checksum, &only_b, &listings)),
+            [5]
+        );
+        // Even with nothing listed, snapshots showing up deeper in the crate get found
+        let nested = crate_dir.join("tests/snapshots");
+        fs::create_dir_all(&nested).unwrap();
+        let empty = [8; 32];
+        assert!(listed_snapshots(&nested, &empty, &globs, &listings).is_empty());
+        fs::create_dir(nested.join("deeper")).unwrap();
+        assert!(listed_snapshots(&nested, &empty, &globs, &listings).is_empty());
+        fs::write(nested.join("deeper/c.snap"), "nested").unwrap();
+        assert_eq!(
+            sizes(listed_snapshots(&nested, &empty, &globs, &listings)),
+            [6]
+        );
+    }
+
+    fn hex_sha256(contents: &[u8]) -> String {
+        Sha256::digest(contents)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    #[test]
+    fn greps_snapshot_lines() {
+        let dir = temp_dir("greps-snapshot-lines");
+        let path = dir.join("a.snap");
+        fs::write(&path, "---\nthread 'main' panicked\nok\npanicked again").unwrap();
+        let grep = BytesRegex::new("panicked").unwrap();
+        assert_eq!(matching_lines(&path, &grep).unwrap(), 2);
+        let grep = BytesRegex::new("^nope$").unwrap();
+        assert_eq!(matching_lines(&path, &grep).unwrap(), 0);
+    }
+
+    #[test]
+    fn collected_names() {
+        let mut used = HashSet::new();
+        let mut name = |path: &str| collected_name("foo-0.1.0", Path::new(path), &mut used);
+        assert_eq!(
+            name("tests/snapshots/a.snap"),
+            "foo-0.1.0__tests_snapshots_a.snap"
+        );
+        assert_eq!(name("b.snap.new"), "foo-0.1.0__b.snap.new");
+        // Flattening can make different paths collide
+        assert_eq!(
+            name("tests_snapshots/a.snap"),
+            "foo-0.1.0__tests_snapshots_a-1.snap"
+        );
+        assert_eq!(
+            name("tests/snapshots_a.snap"),
+            "foo-0.1.0__tests_snapshots_a-2.snap"
+        );
+    }
+
+    #[test]
+    fn crates_io_hosts() {
+        assert!(is_crates_io("https://crates.io/api/v1/crates"));
+        assert!(is_crates_io("https://static.crates.io/crates"));
+        assert!(!is_crates_io("https://crates.io.example.com/crates"));
+        assert!(!is_crates_io("http://localhost:8080/crates.io/{crate}"));
+    }
+
+    #[test]
+    fn custom_snapshot_globs() {
+        let globs = SnapshotGlobs::new(&["tests/snapshots/*.snap", "**/*.pending"]).unwrap();
+        assert!(globs.is_snapshot(Path::new("tests/snapshots/a.snap")));
+        assert!(globs.is_snapshot(Path::new("src/nested/b.pending")));
+        assert!(!globs.is_snapshot(Path::new("src/snapshots/a.snap")));
+        assert!(!globs.is_snapshot(Path::new("tests/snapshots/a.snap.new")));
+        assert!(SnapshotGlobs::new(&["[unclosed"]).is_err());
+    }
+}
@@ -1,299 +1,627 @@
 use std::{
-    collections::BTreeSet,
-    env,
-    ffi::OsString,
-    fs::File,
-    hash::{Hash, Hasher},
-    io::{self, BufReader},
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fs, io,
     num::NonZeroUsize,
-    ops::Deref,
     path::{Path, PathBuf},
-    thread::sleep,
-    time::Duration,
+    process, thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result};
-use clap::Parser;
-use colored::{Color, Colorize};
-use crates_index::{Index, Version};
-use flate2::bufread::GzDecoder;
-use indicatif::{ProgressBar, ProgressStyle};
-use rayon::{prelude::*, ThreadPoolBuilder};
-use tar::Archive;
+use crates_index::{DependencyKind, Index, IndexConfig};
+use dumpsta::{
+    dialog::{Bytes, Dialog, Verbosity},
+    diff::{MatchDiff, MatchSet},
+    disps,
+    state::{Ledger, Listings, ScanRecord, State},
+    BarStyle, CargoRegistry, CrateRecord, CratesIoMeta, DepFilter, DepStats, Download,
+    DownloadOpts, RequirementRecord, RunSummary, SnapshotGlobs, VersionExt, VersionSelect,
+    MIN_REQUEST_DELAY_MS,
+};
+use rayon::ThreadPoolBuilder;
+use tracing_subscriber::EnvFilter;
 
 mod cli;
-mod dialog;
+mod config;
 
-use dialog::{disps, Dialog};
-use ureq::Agent;
+// Errors bubbling out of `main()` exit with 1 instead. These are documented in `cli::EXIT_CODES`
+const CRATE_FAILURES_EXIT_CODE: i32 = 2;
 
-#[derive(Debug, Clone)]
-struct CargoRegistry {
-    base: PathBuf,
-    index_name: OsString,
-}
+// Relative to wherever we're run from
+const DEFAULT_OUT_DIR: &str = "dumpsta-out";
 
-impl CargoRegistry {
-    pub fn new() -> Result<Self> {
-        let base = match env::var_os("CARGO_HOME") {
-            Some(home) => PathBuf::from(home),
-            None => {
-                let home_dir = dirs::home_dir().context("Failed to get home dir")?;
-                PathBuf::from(home_dir).join(".cargo")
-            }
-        }
-        .join("registry");
-
-        let index_name = base
-            .join("cache")
-            .read_dir()
-            .ok()
-            .and_then(|mut dirs| match dirs.next() {
-                Some(Ok(entry)) => Some(entry.file_name()),
-                _ => None,
-            })
-            .context("Cargo home doesn't seem to exist :(")?;
+// Scanning is CPU bound, so going much past this only adds overhead
+const MAX_THREADS_PER_CPU: usize = 4;
 
-        Ok(CargoRegistry { base, index_name })
+fn main() {
+    if let Err(e) = run() {
+        report_error(e);
+        process::exit(1);
     }
+}
 
-    pub fn cache(&self) -> PathBuf {
-        self.sub_dir("cache")
+fn run() -> Result<()> {
+    match cli::Args::parse()? {
+        cli::Args::Scan(args) => scan(args, None),
+        cli::Args::Crate(cli::CrateArgs { spec, scan: args }) => scan(args, Some(spec)),
+        cli::Args::List(args) => list(args),
+        cli::Args::Stats(common) => stats(common),
     }
+}
 
-    pub fn index(&self) -> PathBuf {
-        self.sub_dir("index")
+// Each cause in the chain gets nested under the one it caused
+fn report_error(e: anyhow::Error) {
+    let causes: Vec<_> = e.chain().skip(1).map(ToString::to_string).collect();
+    let dialog = Dialog::raw_with_indent(NonZeroUsize::new(1).unwrap());
+    let reported = dialog
+        .error_with("Error: {}", disps![e])
+        .and_then(|mut dialog| {
+            for cause in &causes {
+                dialog = dialog.error_with("Caused by: {}", disps![cause])?;
+            }
+            Ok(())
+        });
+    // Shouldn't happen, but the error still needs to get out somehow
+    if let Err(dialog_err) = reported {
+        eprintln!("Error: {}", dialog_err);
     }
+}
 
-    pub fn src(&self) -> PathBuf {
-        self.sub_dir("src")
+fn list(args: cli::ListArgs) -> Result<()> {
+    let cli::ListArgs {
+        common,
+        show_requirements,
+    } = args;
+    setup_output(&common)?;
+    let format = common.format;
+    let filter = dep_filter(&common);
+    let Candidates {
+        mut dependents,
+        config,
+        ..
+    } = find_dependents(&common, &filter)?;
+    dependents.sort_by(|a, b| (a.name(), a.version()).cmp(&(b.name(), b.version())));
+    // Only the listing goes to stdout, so that it can be piped elsewhere
+    match format {
+        cli::Format::Pretty => {
+            for version in &dependents {
+                if show_requirements {
+                    let requirements: Vec<_> = RequirementRecord::all(version, &filter)
+                        .iter()
+                        .map(|req| format!("{} {} ({})", req.dep, req.requirement, req.kind))
+                        .collect();
+                    println!(
+                        "{} {} {}",
+                        version.name(),
+                        version.version(),
+                        requirements.join(", ")
+                    );
+                } else {
+                    println!("{} {}", version.name(), version.version());
+                }
+            }
+        }
+        cli::Format::Json => print_records(&dependents, &config, &filter)?,
     }
 
-    fn sub_dir(&self, dir: &'static str) -> PathBuf {
-        self.base.join(dir).join(&self.index_name)
-    }
+    Ok(())
 }
 
-#[derive(Debug, Clone)]
-struct LocalCrates {
-    listing: BTreeSet<String>,
+fn stats(common: cli::CommonArgs) -> Result<()> {
+    setup_output(&common)?;
+    let filter = dep_filter(&common);
+    let Candidates { dependents, .. } = find_dependents(&common, &filter)?;
+    let stats = DepStats::new(&filter, &dependents);
+    match common.format {
+        cli::Format::Pretty => stats.report()?,
+        cli::Format::Json => println!("{}", serde_json::to_string(&stats)?),
+    }
+
+    Ok(())
 }
 
-impl LocalCrates {
-    fn new() -> Result<Self> {
-        let registry = CargoRegistry::new()?;
-
-        // Read over entries ignoring any errors
-        let listing = registry
-            .src()
-            .read_dir()?
-            .filter_map(Result::ok)
-            .filter_map(|entry| entry.file_name().to_str().map(ToOwned::to_owned))
-            .collect();
-        Ok(Self { listing })
+// Pulls the single crate from `spec` in place of the scan's matches when it's passed
+fn scan(args: cli::ScanArgs, spec: Option<cli::CrateSpec>) -> Result<()> {
+    let cli::ScanArgs {
+        common,
+        dry_run,
+        diff,
+        out_dir,
+        snapshot_glob,
+        grep,
+        collect_dir,
+        retries,
+        limit,
+        max_crate_size,
+        sort,
+        request_delay,
+        user_agent,
+        timeout_total,
+        connect_timeout,
+        read_timeout,
+        offline,
+        registry_url,
+        registry,
+        download_jobs,
+        crate_dir,
+        keep_crate: _,
+        no_keep_crate,
+        manifest,
+        #[cfg(feature = "sqlite")]
+        sqlite,
+        dedup,
+        fail_on_error,
+        keep_going: _,
+        progress_style,
+        progress_template,
+        resume,
+        no_resume: _,
+        skip_snapshotless,
+        report_stale,
+        prune_stale,
+        json_lines,
+        since,
+        with_crates_io_meta,
+        exclude,
+        exclude_file,
+    } = args;
+    let format = common.format;
+    if sort.needs_crates_io_meta() && !with_crates_io_meta {
+        anyhow::bail!(
+            "`--sort downloads` and `--sort recent` need details that the index doesn't have. \
+             Pass `--with-crates-io-meta` to look them up through the crates.io API"
+        );
     }
-
-    fn contains(&self, version: &VersionExt) -> bool {
-        let key = format!("{}-{}", version.name(), version.version());
-        self.listing.contains(&key)
+    // The time limit covers scanning too
+    let deadline = timeout_total.map(|secs| Instant::now() + Duration::from_secs(secs));
+
+    setup_output(&common)?;
+    let registry = CargoRegistry::new(registry.as_deref())?;
+    let out_dir = match out_dir {
+        Some(out_dir) => out_dir,
+        None => {
+            let out_dir = PathBuf::from(DEFAULT_OUT_DIR);
+            // Extracting only the snapshots into cargo's registry would look like broken crates
+            // to cargo, so that has to be asked for
+            if registry.contains(&out_dir) {
+                anyhow::bail!(
+                    "Refusing to extract into the cargo registry. Pass `--out-dir` to do it anyways"
+                );
+            }
+            out_dir
+        }
+    };
+    Dialog::new_with("Using the output dir {}", disps![&out_dir])?;
+    let snapshot_globs = SnapshotGlobs::new(&snapshot_glob)?;
+    if offline {
+        return dumpsta::scan_local_snapshots(&registry, &out_dir, &snapshot_globs);
     }
-}
 
-#[derive(Debug, Clone)]
-struct VersionExt(Version);
+    let Candidates {
+        index,
+        mut dependents,
+        mut config,
+        num_scanned,
+    } = match &spec {
+        Some(spec) => find_crate(&common, spec)?,
+        None => find_dependents(&common, &dep_filter(&common))?,
+    };
+    if report_stale || prune_stale {
+        // A single crate says nothing about whether the others still use the deps
+        if spec.is_some() {
+            anyhow::bail!("Finding stale crates needs a full scan instead of a single crate");
+        }
+        let filter = dep_filter(&common);
+        dumpsta::report_stale(&out_dir, &filter, &dependents, prune_stale && !dry_run)?;
+    }
+    if let Some(registry_url) = registry_url {
+        config.dl = registry_url;
+    }
+    let excluded = excluded_crates(exclude, exclude_file.as_deref())?;
+    if !excluded.is_empty() {
+        let num_dependents = dependents.len();
+        dependents.retain(|version| !excluded.contains(version.name()));
+        Dialog::new_with(
+            "Excluded {} candidate crates",
+            disps![num_dependents - dependents.len()],
+        )?;
+    }
+    let ledger = resume.as_deref().map(Ledger::open).transpose()?;
+    if let Some(ledger) = &ledger {
+        let num_dependents = dependents.len();
+        dependents.retain(|version| !ledger.contains(&version.dir_name()));
+        Dialog::new_with(
+            "Resuming past {} crates from the ledger",
+            disps![num_dependents - dependents.len()],
+        )?;
+    }
+    if skip_snapshotless {
+        let state = State::load()?;
+        let num_dependents = dependents.len();
+        dependents.retain(|version| !state.snapshotless.contains(&version.dir_name()));
+        Dialog::new_with(
+            "Skipping {} crates that had no snapshots in earlier runs",
+            disps![num_dependents - dependents.len()],
+        )?;
+    }
+    // Looked up once for everything that needs it
+    let meta = if since.is_some() || sort.needs_crates_io_meta() {
+        Some(dumpsta::crates_io_meta(
+            &dependents,
+            since.is_some(),
+            &user_agent,
+            retries,
+        )?)
+    } else {
+        None
+    };
+    if let (Some(since), Some(meta)) = (since, &meta) {
+        dependents = dumpsta::published_since(dependents, since, meta)?;
+    }
+    if format == cli::Format::Json {
+        // Only the JSON goes to stdout, so that it can be piped elsewhere
+        print_records(&dependents, &config, &dep_filter(&common))?;
+    }
 
-impl VersionExt {
-    fn new(version: Version) -> Self {
-        Self(version)
+    let match_diff = match diff {
+        Some(prev) => Some(MatchDiff::new(
+            &MatchSet::load(&prev)?,
+            &MatchSet::from_versions(&dependents),
+        )),
+        None => None,
+    };
+
+    let num_matched = dependents.len();
+    let listings = Listings::new(Listings::default_dir()?);
+    let (mut to_download, local) =
+        dumpsta::get_uninstalled(&registry, &out_dir, dependents, &snapshot_globs, &listings)?;
+    let summary = RunSummary {
+        scanned: num_scanned,
+        matched: num_matched,
+        already_local: local.len(),
+        // The snapshots in the already local crates were found without pulling anything
+        snapshots_found: local.iter().map(|local| local.snapshots.len()).sum(),
+        snapshotless: local
+            .iter()
+            .filter(|local| local.snapshots.is_empty())
+            .count(),
+        ..RunSummary::default()
+    };
+    // Sorted so that repeated runs with the same limit pull the same crates
+    sort_versions(&mut to_download, sort, &index, meta.as_ref());
+    let mut downloads: Vec<_> = to_download
+        .into_iter()
+        .filter_map(|version| {
+            let url = version.download_url(&config)?;
+            Some(Download { version, url })
+        })
+        .collect();
+    let crate_dir = crate_dir.unwrap_or_else(|| registry.cache());
+    // Skipped before limiting so that the limit still gets filled up
+    let skipped_too_large = match max_crate_size {
+        Some(max_size) => dumpsta::skip_too_large(&crate_dir, &mut downloads, max_size)?,
+        None => 0,
+    };
+    let summary = RunSummary {
+        too_large: skipped_too_large,
+        ..summary
+    };
+    let num_candidates = downloads.len();
+    if let Some(limit) = limit {
+        if limit < num_candidates {
+            downloads.truncate(limit);
+            Dialog::new_with(
+                "Limiting to {} of {} candidate crates",
+                disps![limit, num_candidates],
+            )?;
+        }
     }
 
-    fn inner(&self) -> &Version {
-        &self.0
+    if dry_run {
+        if let Some(match_diff) = &match_diff {
+            match_diff.report()?;
+            if format == cli::Format::Json {
+                println!("{}", serde_json::to_string(match_diff)?);
+            }
+        }
+        let (cached_bytes, num_cached) = dumpsta::cached_size(&crate_dir, &downloads);
+        let size_dialog = Dialog::raw_with_indent(NonZeroUsize::new(1).unwrap());
+        if num_cached != 0 {
+            size_dialog.info_with(
+                "{} are already cached ({})",
+                disps![num_cached, Bytes(cached_bytes)],
+            )?;
+        }
+        if num_cached < downloads.len() {
+            size_dialog.info_with(
+                "The size of the other {} is unknown since the index doesn't record crate sizes",
+                disps![downloads.len() - num_cached],
+            )?;
+        }
+        Dialog::summary_with(
+            "Finished dry run! Would download {} of {} crates",
+            disps![downloads.len(), num_candidates],
+        )?;
+        return report_summary(&summary, format);
     }
-}
 
-impl From<Version> for VersionExt {
-    fn from(version: Version) -> Self {
-        Self::new(version)
+    fs::create_dir_all(&out_dir)
+        .with_context(|| format!("Failed creating out dir {}", out_dir.display()))?;
+    fs::create_dir_all(&crate_dir)
+        .with_context(|| format!("Failed creating crate dir {}", crate_dir.display()))?;
+    // Don't let anyone accidentally go past the crawling policy's one request per second. Mirrors
+    // and private registries have their own policies
+    let request_delay = if request_delay < MIN_REQUEST_DELAY_MS && dumpsta::is_crates_io(&config.dl)
+    {
+        Dialog::raw_with_indent(NonZeroUsize::new(1).unwrap()).warn_with(
+            "Raising the request delay from {}ms to the minimum of {}ms",
+            disps![request_delay as usize, MIN_REQUEST_DELAY_MS as usize],
+        )?;
+        MIN_REQUEST_DELAY_MS
+    } else {
+        request_delay
+    };
+    let opts = DownloadOpts {
+        out_dir,
+        retries,
+        request_delay: Duration::from_millis(request_delay),
+        user_agent,
+        connect_timeout: Duration::from_secs(connect_timeout),
+        read_timeout: Duration::from_secs(read_timeout),
+        jobs: download_jobs,
+        crate_dir,
+        keep_crate: !no_keep_crate,
+        manifest,
+        dedup,
+        fail_fast: fail_on_error,
+        bar_style: match (progress_template, progress_style) {
+            (Some(template), _) => BarStyle::Template(template),
+            (None, cli::ProgressStyleChoice::Default) => BarStyle::Default,
+            (None, cli::ProgressStyleChoice::Plain) => BarStyle::Plain,
+            (None, cli::ProgressStyleChoice::Bytes) => BarStyle::Bytes,
+        },
+        snapshot_globs,
+        grep,
+        collect_dir,
+        ledger,
+        json_lines,
+        deadline,
+        listings,
+        max_crate_size,
+        #[cfg(feature = "sqlite")]
+        sqlite: sqlite.map(|path| dumpsta::sqlite::Export {
+            path,
+            filter: dep_filter(&common),
+        }),
+    };
+    dumpsta::interrupt::install_handler()?;
+    let pulled = dumpsta::download_crates(&downloads, local, &opts)?;
+    let summary = RunSummary {
+        downloaded: pulled.downloaded,
+        from_cache: pulled.from_cache,
+        failed: pulled.failed,
+        snapshots_found: pulled.snapshots_found,
+        snapshotless: pulled.snapshotless,
+        grep_matches: pulled.grep_matches,
+        too_large: summary.too_large + pulled.too_large,
+        timed_out: pulled.timed_out,
+        ..summary
+    };
+    report_summary(&summary, format)?;
+    if summary.failed != 0 {
+        process::exit(CRATE_FAILURES_EXIT_CODE);
     }
-}
 
-impl Deref for VersionExt {
-    type Target = Version;
+    Ok(())
+}
 
-    fn deref(&self) -> &Self::Target {
-        self.inner()
+// Configures the global output and thread pool shared by every subcommand
+fn setup_output(common: &cli::CommonArgs) -> Result<()> {
+    // Both `colored` and the styles in our `indicatif` templates need to be told
+    let use_color = common.color.should_colorize();
+    colored::control::set_override(use_color);
+    console::set_colors_enabled_stderr(use_color);
+
+    // Logs are for debugging, so they're off unless asked for and kept apart from the dialogs
+    let log_filter = match common.log_level {
+        Some(level) => EnvFilter::default().add_directive(level.into()),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("off")),
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(log_filter)
+        .with_writer(io::stderr)
+        .with_ansi(use_color)
+        .init();
+
+    let verbosity = if common.quiet {
+        Verbosity::Quiet
+    } else if common.verbose {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    };
+    verbosity.set_global();
+
+    let warn_dialog = Dialog::raw_with_indent(NonZeroUsize::new(1).unwrap());
+    let cli::Threads(scan_threads) = match common.threads {
+        Some(threads) => {
+            warn_dialog.warn(
+                "`--threads` is deprecated and will be removed. Use `--scan-threads` instead",
+            )?;
+            threads
+        }
+        None => common.scan_threads,
+    };
+    if let (Some(threads), Ok(num_cpus)) = (scan_threads, thread::available_parallelism()) {
+        if threads.get() > num_cpus.get() * MAX_THREADS_PER_CPU {
+            warn_dialog.warn_with(
+                "Using {} scan threads even though there are only {} CPUs",
+                disps![threads.get(), num_cpus.get()],
+            )?;
+        }
     }
+    // `0` lets rayon pick one per CPU
+    ThreadPoolBuilder::new()
+        .num_threads(scan_threads.map_or(0, NonZeroUsize::get))
+        .build_global()?;
+
+    Ok(())
 }
 
-impl PartialEq for VersionExt {
-    fn eq(&self, other: &Self) -> bool {
-        self.name() == other.name() && self.version() == other.version()
+fn dep_filter(common: &cli::CommonArgs) -> DepFilter {
+    DepFilter {
+        names: common.deps.clone(),
+        req: common.dep_req.clone(),
+        kinds: common
+            .kinds
+            .iter()
+            .copied()
+            .map(DependencyKind::from)
+            .collect(),
+        include_optional: common.include_optional,
+        only_target: common.only_target.clone(),
+        crate_filter: common.crate_filter.clone(),
+        yank_policy: common.yank_policy.into(),
+        include_pre: common.include_pre,
     }
 }
 
-impl Hash for VersionExt {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.name().hash(state);
-        self.version().hash(state);
+fn version_select(common: &cli::CommonArgs) -> VersionSelect {
+    if common.all_versions {
+        VersionSelect::All
+    } else {
+        common.version_select.into()
     }
 }
 
-fn err(err: impl std::error::Error + Send + Sync + 'static) -> anyhow::Error {
-    err.into()
+// The crates that turned up in the scan
+struct Candidates {
+    index: Index,
+    dependents: Vec<VersionExt>,
+    config: IndexConfig,
+    num_scanned: Option<u64>,
 }
 
-fn reverse_dependents_for(index: &Index, crate_name: &str) -> Vec<VersionExt> {
-    index
-        .crates_parallel()
-        .filter_map(|maybe_krate| maybe_krate.ok())
-        .map(|krate| krate.highest_version().to_owned())
-        .map(VersionExt::from)
-        .filter(|version| {
-            version
-                .dependencies()
-                .iter()
-                .any(|dep| dep.crate_name() == crate_name)
-        })
-        .collect()
+fn find_dependents(common: &cli::CommonArgs, filter: &DepFilter) -> Result<Candidates> {
+    let mut index = dumpsta::open_index(common.index_url.as_deref(), common.index_path.as_deref())?;
+    let mut state = State::load()?;
+    let cache_path = match &common.cache_scan {
+        Some(path) => path.to_owned(),
+        None => ScanRecord::default_path()?,
+    };
+    let updated = common.force_update_index
+        && dumpsta::try_force_update_index(&mut index, &mut state, common.force_update_interval)?;
+    let config = index.index_config()?;
+    let (dependents, num_scanned) = dumpsta::load_or_find_dependents(
+        &index,
+        &cache_path,
+        filter,
+        version_select(common),
+        updated || common.refresh,
+        common.max_age,
+    )?;
+
+    Ok(Candidates {
+        index,
+        dependents,
+        config,
+        num_scanned,
+    })
 }
 
-fn get_uninstalled_insta_dependents() -> Result<Vec<VersionExt>> {
-    let spinner = ProgressBar::new(1).with_style(
-        ProgressStyle::default_spinner()
-            .template("{elapsed:>3.green.bold} {spinner:.blue.bold} {msg:!.bold}"),
-    );
-    spinner.set_message("Finding all current crates that use `insta`...");
-    spinner.enable_steady_tick(100);
-    let index = Index::new_cargo_default()?;
-    let uses_insta = reverse_dependents_for(&index, "insta");
-    spinner.finish();
-    Dialog::raw_with_indent(NonZeroUsize::new(1).unwrap()).info_with(
-        "Found {} crates that use `insta`!",
-        disps![uses_insta.len()],
-    );
-
-    // Check to see which ones we already have installed
-    let scan_dialog = Dialog::new("Scanning locally downloaded crates...");
-    let local_crates = LocalCrates::new()?;
+fn find_crate(common: &cli::CommonArgs, spec: &cli::CrateSpec) -> Result<Candidates> {
+    let index = dumpsta::open_index(common.index_url.as_deref(), common.index_path.as_deref())?;
     let config = index.index_config()?;
-    let to_download: Vec<_> = uses_insta
-        .into_iter()
-        .filter(|version| !local_crates.contains(version))
-        .collect();
-    if to_download.len() == 0 {
-        scan_dialog.info("No crates to download!");
-    } else {
-        scan_dialog.info_with("{} crates to download", disps![to_download.len()]);
-    }
-
-    Ok(to_download)
+    let version = dumpsta::find_crate_version(
+        &index,
+        &spec.name,
+        spec.version.as_deref(),
+        &dep_filter(common),
+    )?;
+    Dialog::new_with("Pulling {}", disps![&version])?;
+
+    Ok(Candidates {
+        index,
+        dependents: vec![version],
+        config,
+        num_scanned: None,
+    })
 }
 
-fn download_crate(agent: &Agent, registry: &CargoRegistry, url: &str) -> Result<String> {
-    // Download file
-    let resp = agent.get(url).call()?;
-    let file_name = resp.get_url().rsplit_once('/').unwrap().1.to_owned();
-    let dl_path = registry.cache().join(&file_name);
-    let mut dl_file = File::create(&dl_path)?;
-    let mut reader = BufReader::new(resp.into_reader());
-    io::copy(&mut reader, &mut dl_file)?;
-
-    // Extract contents
-    let reader = File::open(&dl_path)?;
-    let decompressor = GzDecoder::new(BufReader::new(reader));
-    let mut archive = Archive::new(decompressor);
-    archive.unpack(&registry.src())?;
-
-    Ok(file_name)
+fn report_summary(summary: &RunSummary, format: cli::Format) -> Result<()> {
+    summary.report()?;
+    if format == cli::Format::Json {
+        println!("{}", serde_json::to_string(summary)?);
+    }
+
+    Ok(())
 }
 
-fn download_crates(urls: &[String]) -> Result<()> {
-    let counter = format!(
-        "{}{{pos:.cyan.bold}}{}{{len:.cyan.bold}}{}",
-        "(".cyan().bold(),
-        "/".cyan().bold(),
-        ")".cyan().bold(),
-    );
-    let eta = format!("{} {{eta:<3.green.bold}}", "eta".green().bold());
-    let pb = ProgressBar::new(urls.len() as u64).with_style(
-        ProgressStyle::default_bar()
-            .template(&format!(
-                "{} {} {}{{bar:60.blue}}{} {}",
-                "Downloading:".bold(),
-                counter,
-                "|".bold(),
-                "|".bold(),
-                eta,
-            ))
-            .progress_chars("█▉▊▋▌▍▎▏ "),
-    );
-    let full_dl_dialog = Dialog::new("Downloading crates...");
-    let cargo_registry = CargoRegistry::new()?;
-    let agent = ureq::builder()
-        // Setting a description user agent per crates.io crawling policy
-        .user_agent("dumpsta (github.com/LovecraftianHorror/dumpsta)")
-        .build();
-    let mut num_install_errors = 0;
-    for url in pb.wrap_iter(urls.iter()) {
-        // Performing at most one request per second per crates.io crawling policy
-        sleep(Duration::from_secs(1));
-        let (crate_dl_dialog, msg) = full_dl_dialog.info_str_with("Downloading {}...", disps![url]);
-        pb.println(msg);
-
-        match download_crate(&agent, &cargo_registry, &url) {
-            Ok(file_name) => {
-                let (_, msg) = crate_dl_dialog.msg_str_with(
-                    Color::Green,
-                    "Downloaded and extracted {}",
-                    disps![file_name],
-                );
-                pb.println(msg);
-            }
-            Err(e) => {
-                crate_dl_dialog.warn_with("Failed download for {}, Err: {}", disps![url, e]);
-                num_install_errors += 1;
-                continue;
-            }
+// `meta` has to be there for the orders that need it. Ties and crates missing the details go by
+// name, with the missing ones last
+fn sort_versions(
+    versions: &mut [VersionExt],
+    sort: cli::SortChoice,
+    index: &Index,
+    meta: Option<&HashMap<String, CratesIoMeta>>,
+) {
+    let empty = HashMap::new();
+    let meta = meta.unwrap_or(&empty);
+    let meta_of = |version: &VersionExt| meta.get(&version.dir_name());
+    match sort {
+        cli::SortChoice::Downloads => versions.sort_by_cached_key(|version| {
+            let downloads = meta_of(version).map(|meta| meta.downloads);
+            (Reverse(downloads), version.dir_name())
+        }),
+        cli::SortChoice::Name => {
+            // Versions that aren't valid semver sort before the rest
+            let semver = |version: &VersionExt| semver::Version::parse(version.version()).ok();
+            versions.sort_by(|a, b| {
+                a.name()
+                    .cmp(b.name())
+                    .then_with(|| semver(a).cmp(&semver(b)))
+            })
+        }
+        cli::SortChoice::Version => {
+            let newer = dumpsta::newer_versions(index, versions);
+            versions.sort_by_cached_key(|version| {
+                let num_newer = newer.get(&version.dir_name()).copied();
+                (num_newer.unwrap_or(usize::MAX), version.dir_name())
+            })
         }
+        cli::SortChoice::Recent => versions.sort_by_cached_key(|version| {
+            let published = meta_of(version).map(|meta| meta.published.clone());
+            (Reverse(published), version.dir_name())
+        }),
     }
+}
 
-    if num_install_errors != 0 {
-        full_dl_dialog.warn_with("Failed pulling {} crates", disps![num_install_errors]);
+fn excluded_crates(names: Vec<String>, file: Option<&Path>) -> Result<HashSet<String>> {
+    let mut excluded: HashSet<_> = names.into_iter().collect();
+    if let Some(file) = file {
+        let contents = fs::read_to_string(file)
+            .with_context(|| format!("Failed reading exclude file {}", file.display()))?;
+        let names = contents
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|name| !name.is_empty())
+            .map(ToOwned::to_owned);
+        excluded.extend(names);
     }
 
-    Ok(())
+    Ok(excluded)
 }
 
-// TODO: check cached and extracted files
-// TODO: Add a flag for force updating the index
-// - Have this store a flag and limit. We don't need people to force updates all the time
-// TODO: display the error with our `Dialog` stuff
-// TODO: Add a check to avoid scanning the full index
-// - A simple timestamp on the last check should be enough
-// TODO: Have a default out dir and an option to override
-// TODO: Check if installed, then cached, then download if needed
-fn main() -> Result<()> {
-    let cli::Args { dry_run, threads } = cli::Args::parse();
-
-    ThreadPoolBuilder::new()
-        .num_threads(threads)
-        .build_global()?;
-
-    let to_download = get_uninstalled_insta_dependents()?;
-    let index = Index::new_cargo_default()?;
-    let config = index.index_config()?;
-    let download_urls: Vec<_> = to_download
+fn print_records(
+    dependents: &[VersionExt],
+    config: &IndexConfig,
+    filter: &DepFilter,
+) -> Result<()> {
+    let records: Vec<_> = dependents
         .iter()
-        .filter_map(|version| version.download_url(&config))
+        .map(|version| CrateRecord::new(version, config, filter))
         .collect();
-
-    if dry_run {
-        Dialog::new("Finished dry run!");
-        return Ok(());
-    }
-
-    download_crates(&download_urls)?;
-
+    println!("{}", serde_json::to_string(&records)?);
     Ok(())
 }
@@ -0,0 +1,212 @@
+use std::cmp::Ordering;
+
+use semver::{Comparator, Op, Prerelease, Version, VersionReq};
+
+// A bound on a range of versions where `None` means unbounded and the `bool` is whether the bound
+// is inclusive
+type Bound = Option<(Version, bool)>;
+
+// The half-open-ish range of versions that a `VersionReq` can match
+struct Range {
+    lower: Bound,
+    upper: Bound,
+}
+
+impl Range {
+    fn any() -> Self {
+        Self {
+            lower: None,
+            upper: None,
+        }
+    }
+
+    fn from_req(req: &VersionReq) -> Self {
+        // All of the comparators have to match, so the range is the intersection of all of them
+        req.comparators
+            .iter()
+            .map(Self::from_comparator)
+            .fold(Self::any(), |acc, range| acc.intersect(range))
+    }
+
+    fn from_comparator(cmp: &Comparator) -> Self {
+        let major = cmp.major;
+        let minor = cmp.minor.unwrap_or(0);
+        let patch = cmp.patch.unwrap_or(0);
+        let mut base = Version::new(major, minor, patch);
+        base.pre = cmp.pre.clone();
+
+        // The smallest version that is past everything matched by the specified components
+        let next = match (cmp.minor, cmp.patch) {
+            (None, _) => Version::new(major + 1, 0, 0),
+            (Some(minor), None) => Version::new(major, minor + 1, 0),
+            (Some(minor), Some(patch)) => Version::new(major, minor, patch + 1),
+        };
+
+        let at_least = |version| Some((version, true));
+        let below = |version| Some((version, false));
+
+        match cmp.op {
+            Op::Exact | Op::Wildcard => match cmp.patch {
+                Some(_) => Self {
+                    lower: at_least(base.clone()),
+                    upper: Some((base, true)),
+                },
+                None => Self {
+                    lower: at_least(base),
+                    upper: below(next),
+                },
+            },
+            Op::Greater => match cmp.patch {
+                Some(_) => Self {
+                    lower: Some((base, false)),
+                    upper: None,
+                },
+                None => Self {
+                    lower: at_least(next),
+                    upper: None,
+                },
+            },
+            Op::GreaterEq => Self {
+                lower: at_least(base),
+                upper: None,
+            },
+            Op::Less => Self {
+                lower: None,
+                upper: below(base),
+            },
+            Op::LessEq => match cmp.patch {
+                Some(_) => Self {
+                    lower: None,
+                    upper: Some((base, true)),
+                },
+                None => Self {
+                    lower: None,
+                    upper: below(next),
+                },
+            },
+            Op::Tilde => {
+                let upper = match cmp.minor {
+                    Some(minor) => Version::new(major, minor + 1, 0),
+                    None => Version::new(major + 1, 0, 0),
+                };
+                Self {
+                    lower: at_least(base),
+                    upper: below(upper),
+                }
+            }
+            Op::Caret => {
+                let upper = match (major, cmp.minor, cmp.patch) {
+                    (0, Some(0), Some(patch)) => Version::new(0, 0, patch + 1),
+                    (0, Some(minor), _) => Version::new(0, minor + 1, 0),
+                    _ => Version::new(major + 1, 0, 0),
+                };
+                Self {
+                    lower: at_least(base),
+                    upper: below(upper),
+                }
+            }
+            // Unknown operators are treated permissively so that we don't throw out matches
+            _ => Self::any(),
+        }
+    }
+
+    fn intersect(self, other: Self) -> Self {
+        let lower = match (self.lower, other.lower) {
+            (None, bound) | (bound, None) => bound,
+            (Some(a), Some(b)) => Some(match a.0.cmp(&b.0) {
+                Ordering::Greater => a,
+                Ordering::Less => b,
+                Ordering::Equal => (a.0, a.1 && b.1),
+            }),
+        };
+        let upper = match (self.upper, other.upper) {
+            (None, bound) | (bound, None) => bound,
+            (Some(a), Some(b)) => Some(match a.0.cmp(&b.0) {
+                Ordering::Less => a,
+                Ordering::Greater => b,
+                Ordering::Equal => (a.0, a.1 && b.1),
+            }),
+        };
+
+        Self { lower, upper }
+    }
+
+    fn is_empty(&self) -> bool {
+        match (&self.lower, &self.upper) {
+            (Some((lower, lower_inc)), Some((upper, upper_inc))) => match lower.cmp(upper) {
+                Ordering::Greater => true,
+                Ordering::Equal => !(*lower_inc && *upper_inc),
+                Ordering::Less => false,
+            },
+            // Nothing sits below `0.0.0`
+            (None, Some((upper, false))) => {
+                *upper == Version::new(0, 0, 0) && upper.pre == Prerelease::EMPTY
+            }
+            _ => false,
+        }
+    }
+
+    // Whether there's a version in the range that isn't a pre-release
+    fn has_release(&self) -> bool {
+        let lowest = match &self.lower {
+            None => Version::new(0, 0, 0),
+            // A pre-release sorts right before its release
+            Some((lower, _)) if !lower.pre.is_empty() => {
+                Version::new(lower.major, lower.minor, lower.patch)
+            }
+            Some((lower, true)) => Version::new(lower.major, lower.minor, lower.patch),
+            Some((lower, false)) => Version::new(lower.major, lower.minor, lower.patch + 1),
+        };
+
+        match &self.upper {
+            None => true,
+            Some((upper, inclusive)) => lowest < *upper || (*inclusive && lowest == *upper),
+        }
+    }
+}
+
+// Whether there could be some version that satisfies both requirements. Versions that only
+// overlap on pre-releases only count with `include_pre`
+pub fn overlaps(a: &VersionReq, b: &VersionReq, include_pre: bool) -> bool {
+    let range = Range::from_req(a).intersect(Range::from_req(b));
+    if include_pre {
+        !range.is_empty()
+    } else {
+        range.has_release()
+    }
+}
+
+// A rough description of how tightly `req` constrains the version
+pub fn kind_of(req: &VersionReq) -> &'static str {
+    match req.comparators.as_slice() {
+        [] => "any",
+        [cmp] => match cmp.op {
+            Op::Exact => "pinned",
+            Op::Caret => "caret",
+            Op::Tilde => "tilde",
+            Op::Wildcard => "wildcard",
+            _ => "range",
+        },
+        _ => "range",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlap(a: &str, b: &str, include_pre: bool) -> bool {
+        overlaps(&a.parse().unwrap(), &b.parse().unwrap(), include_pre)
+    }
+
+    #[test]
+    fn pre_release_overlaps() {
+        assert!(overlap("^1.0", "^1.0.0-beta.2", false));
+        assert!(!overlap("^1.0.0-alpha", "=1.0.0-beta.2", false));
+        assert!(overlap("^1.0.0-alpha", "=1.0.0-beta.2", true));
+        assert!(!overlap(">=1.0.0-alpha, <1.0.0", "*", false));
+        assert!(overlap(">=1.0.0-alpha, <1.0.0", "*", true));
+        assert!(!overlap(">1.0.0, <1.0.1", "*", false));
+        assert!(overlap("<=1.0.0", ">=1.0.0", false));
+    }
+}
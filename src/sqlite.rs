@@ -0,0 +1,137 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+
+use crate::{sha256_of, state::hex, DepFilter, ExtractedSnapshot, RequirementRecord, VersionExt};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS crates (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    version TEXT NOT NULL,
+    checksum TEXT NOT NULL UNIQUE,
+    dep_requirement TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS snapshots (
+    crate_id INTEGER NOT NULL REFERENCES crates (id),
+    relative_path TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    content_hash TEXT NOT NULL,
+    PRIMARY KEY (crate_id, relative_path)
+);
+";
+
+// Where `--sqlite` writes to along with the filter that describes each crate's requirements
+pub struct Export {
+    pub path: PathBuf,
+    pub filter: DepFilter,
+}
+
+impl Export {
+    // Crates are keyed by their checksum, so the database builds up across runs while a crate that
+    // gets exported again has its snapshots replaced. Each crate comes with its dir and the dest
+    // that deduped snapshots are relative to. Returns how many crates were written
+    pub fn write<'a>(
+        &self,
+        crates: impl Iterator<Item = (&'a VersionExt, PathBuf, &'a Path, &'a [ExtractedSnapshot])>,
+    ) -> Result<usize> {
+        let mut conn = Connection::open(&self.path)
+            .with_context(|| format!("Failed opening database {}", self.path.display()))?;
+        conn.execute_batch(SCHEMA)?;
+        let tx = conn.transaction()?;
+        let mut num_crates = 0;
+        {
+            let mut upsert_crate = tx.prepare(
+                "INSERT INTO crates (name, version, checksum, dep_requirement)
+                 VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (checksum) DO UPDATE SET
+                     name = excluded.name,
+                     version = excluded.version,
+                     dep_requirement = excluded.dep_requirement
+                 RETURNING id",
+            )?;
+            let mut clear_snapshots = tx.prepare("DELETE FROM snapshots WHERE crate_id = ?1")?;
+            let mut insert_snapshot = tx.prepare(
+                "INSERT INTO snapshots (crate_id, relative_path, size, content_hash)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+
+            for (version, crate_dir, dest, snapshots) in crates {
+                let requirement = RequirementRecord::all(version, &self.filter)
+                    .iter()
+                    .map(|req| format!("{} {}", req.dep, req.requirement))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let crate_id: i64 = upsert_crate.query_row(
+                    params![
+                        version.name(),
+                        version.version(),
+                        hex(version.checksum()),
+                        requirement
+                    ],
+                    |row| row.get(0),
+                )?;
+                clear_snapshots.execute(params![crate_id])?;
+                for snapshot in snapshots {
+                    let location = snapshot.location(&crate_dir, dest);
+                    let content_hash = sha256_of(&location)
+                        .with_context(|| format!("Failed hashing {}", location.display()))?;
+                    insert_snapshot.execute(params![
+                        crate_id,
+                        snapshot.path.to_string_lossy(),
+                        snapshot.size as i64,
+                        hex(&content_hash)
+                    ])?;
+                }
+                num_crates += 1;
+            }
+        }
+        tx.commit()?;
+
+        Ok(num_crates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::tests::{index_entry, temp_dir};
+
+    #[test]
+    fn upserts_by_checksum() {
+        let dir = temp_dir("sqlite-upserts-by-checksum");
+        let crate_dir = dir.join("foo-0.1.0");
+        fs::create_dir_all(&crate_dir).unwrap();
+        fs::write(crate_dir.join("a.snap"), "snap").unwrap();
+        let version = index_entry("foo", "0.1.0", &["insta"], false);
+        let snapshots = [ExtractedSnapshot {
+            path: PathBuf::from("a.snap"),
+            size: 4,
+            duplicate_of: None,
+        }];
+        let export = Export {
+            path: dir.join("results.db"),
+            filter: DepFilter::any_kind("insta"),
+        };
+
+        for _ in 0..2 {
+            let crates = [(&version, crate_dir.clone(), &*dir, &snapshots[..])];
+            assert_eq!(export.write(crates.into_iter()).unwrap(), 1);
+        }
+
+        let conn = Connection::open(&export.path).unwrap();
+        let (name, requirement): (String, String) = conn
+            .query_row("SELECT name, dep_requirement FROM crates", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((name.as_str(), requirement.as_str()), ("foo", "insta ^1"));
+        let num_snapshots: i64 = conn
+            .query_row("SELECT COUNT(*) FROM snapshots", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(num_snapshots, 1);
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use crates_index::{Index, Version};
+use serde::{Deserialize, Serialize};
+
+// Persistent state that's kept between runs
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    // Seconds since the unix epoch
+    pub last_forced_update: Option<u64>,
+    // The `name-version` of pulled crates that didn't have any snapshots
+    #[serde(default)]
+    pub snapshotless: BTreeSet<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScanRecord {
+    // Seconds since the unix epoch
+    pub timestamp: u64,
+    pub index_head: Option<String>,
+    // Describes the options the scan ran with, so that changing them triggers a rescan
+    pub options: String,
+    pub matches: Vec<Version>,
+    // How many crates were in the index, which gives later scans an idea of how long they'll take
+    #[serde(default)]
+    pub num_crates: Option<u64>,
+}
+
+impl ScanRecord {
+    pub fn new(index: &Index, options: String, matches: Vec<Version>, num_crates: u64) -> Self {
+        Self {
+            timestamp: now(),
+            index_head: index_head(index),
+            options,
+            matches,
+            num_crates: Some(num_crates),
+        }
+    }
+
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(now().saturating_sub(self.timestamp))
+    }
+
+    // The record can be reused if it's recent, matches the current options, and the index hasn't
+    // moved on since
+    pub fn is_fresh(&self, index: &Index, options: &str, max_age: Duration) -> bool {
+        self.age() <= max_age && self.options == options && self.index_head == index_head(index)
+    }
+
+    pub fn default_path() -> Result<PathBuf> {
+        Ok(cache_dir()?.join("scan.json"))
+    }
+
+    // Like with the state, a missing or unreadable record just means scanning from scratch
+    pub fn load(path: &Path) -> Option<Self> {
+        let contents = fs::read(path).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        save_json(path, self)
+            .with_context(|| format!("Failed writing scan cache {}", path.display()))
+    }
+}
+
+impl State {
+    pub fn since_forced_update(&self) -> Option<Duration> {
+        self.last_forced_update
+            .map(|timestamp| Duration::from_secs(now().saturating_sub(timestamp)))
+    }
+
+    pub fn record_forced_update(&mut self) {
+        self.last_forced_update = Some(now());
+    }
+
+    pub fn path() -> Result<PathBuf> {
+        Ok(cache_dir()?.join("state.json"))
+    }
+
+    // A missing or unreadable state file just means starting from scratch
+    pub fn load() -> Result<Self> {
+        let state = fs::read(Self::path()?)
+            .ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok())
+            .unwrap_or_default();
+        Ok(state)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path()?;
+        save_json(&path, self)
+            .with_context(|| format!("Failed writing state file {}", path.display()))
+    }
+}
+
+// An append-only record of the `name-version` of every crate that was fully pulled. Unlike checking
+// for local crates this still works after the extracted crates get cleaned up
+#[derive(Debug)]
+pub struct Ledger {
+    done: HashSet<String>,
+    file: Mutex<File>,
+}
+
+impl Ledger {
+    pub fn open(path: &Path) -> Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed reading ledger {}", path.display()))
+            }
+        };
+        // A crash can leave a partially written last line, which doesn't count and gets dropped
+        let mut lines: Vec<_> = contents.split('\n').collect();
+        let partial = lines.pop().unwrap_or_default();
+        let done = lines.into_iter().map(ToOwned::to_owned).collect();
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed opening ledger {}", path.display()))?;
+        if !partial.is_empty() {
+            file.set_len((contents.len() - partial.len()) as u64)?;
+        }
+
+        Ok(Self {
+            done,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn contains(&self, dir_name: &str) -> bool {
+        self.done.contains(dir_name)
+    }
+
+    // Synced right away so that an interrupted run doesn't lose anything that already finished
+    pub fn record(&self, dir_name: &str) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", dir_name)?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+// What a crate's snapshots looked like the last time they were listed. Keyed by the crate's
+// checksum, so a crate that got republished under the same version never reuses an old listing
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Listing {
+    // The snapshot globs the listing was made with
+    pub globs: Vec<String>,
+    pub tree: TreeStamp,
+    pub snapshots: Vec<ListedSnapshot>,
+}
+
+// Changes whenever an entry gets added to, removed from, or renamed in any dir of the crate, which
+// is what catches snapshots that showed up after the crate got listed
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeStamp {
+    pub num_entries: u64,
+    // The newest modified time out of all the dirs in nanoseconds since the unix epoch
+    pub newest_dir: u64,
+}
+
+impl TreeStamp {
+    pub fn new(crate_dir: &Path) -> Option<Self> {
+        let mut stamp = Self {
+            num_entries: 0,
+            newest_dir: stat(crate_dir)?.1,
+        };
+        let mut dirs = vec![crate_dir.to_owned()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir).ok()? {
+                let entry = entry.ok()?;
+                stamp.num_entries += 1;
+                // Links are left alone just like in the snapshot walk
+                if entry.file_type().ok()?.is_dir() {
+                    let path = entry.path();
+                    stamp.newest_dir = stamp.newest_dir.max(stat(&path)?.1);
+                    dirs.push(path);
+                }
+            }
+        }
+
+        Some(stamp)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListedSnapshot {
+    // Relative to the crate's root
+    pub path: PathBuf,
+    pub size: u64,
+    // Nanoseconds since the unix epoch
+    pub modified: u64,
+    pub sha256: String,
+}
+
+impl ListedSnapshot {
+    pub fn new(crate_dir: &Path, path: PathBuf, sha256: &[u8; 32]) -> Option<Self> {
+        let (size, modified) = stat(&crate_dir.join(&path))?;
+        Some(Self {
+            path,
+            size,
+            modified,
+            sha256: hex(sha256),
+        })
+    }
+
+    // Going off of the size and modified time keeps checking as cheap as a `stat()`
+    pub fn is_unchanged(&self, crate_dir: &Path) -> bool {
+        stat(&crate_dir.join(&self.path)) == Some((self.size, self.modified))
+    }
+}
+
+// A dir of `Listing`s with one file per crate so that parallel walks never contend on anything
+#[derive(Debug, Clone)]
+pub struct Listings {
+    dir: PathBuf,
+}
+
+impl Listings {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    pub fn default_dir() -> Result<PathBuf> {
+        Ok(cache_dir()?.join("listings"))
+    }
+
+    // Like the scan record a missing or unreadable listing just means walking the crate again
+    pub fn load(&self, checksum: &[u8; 32]) -> Option<Listing> {
+        let contents = fs::read(self.path_of(checksum)).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+
+    pub fn save(&self, checksum: &[u8; 32], listing: &Listing) -> Result<()> {
+        let path = self.path_of(checksum);
+        save_json(&path, listing)
+            .with_context(|| format!("Failed writing listing {}", path.display()))
+    }
+
+    fn path_of(&self, checksum: &[u8; 32]) -> PathBuf {
+        self.dir.join(format!("{}.json", hex(checksum)))
+    }
+}
+
+fn stat(path: &Path) -> Option<(u64, u64)> {
+    let metadata = path.metadata().ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((metadata.len(), modified.as_nanos() as u64))
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn cache_dir() -> Result<PathBuf> {
+    let cache_dir = dirs::cache_dir().context("Failed to get cache dir")?;
+    Ok(cache_dir.join("dumpsta"))
+}
+
+fn save_json(path: &Path, val: &impl Serialize) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_vec(val)?)?;
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+// The commit that the local index is currently at
+fn index_head(index: &Index) -> Option<String> {
+    ["FETCH_HEAD", "HEAD"].iter().find_map(|ref_file| {
+        let contents = fs::read_to_string(index.path().join(ref_file)).ok()?;
+        let head: String = contents
+            .chars()
+            .take_while(char::is_ascii_hexdigit)
+            .collect();
+        (!head.is_empty()).then_some(head)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_dir;
+
+    #[test]
+    fn ledger_skips_partial_lines() {
+        let dir = temp_dir("ledger-skips-partial-lines");
+        let path = dir.join("ledger");
+        fs::write(&path, "foo-0.1.0\nbar-1.0").unwrap();
+
+        let ledger = Ledger::open(&path).unwrap();
+        assert!(ledger.contains("foo-0.1.0"));
+        assert!(!ledger.contains("bar-1.0"));
+        ledger.record("bar-1.0.0").unwrap();
+        drop(ledger);
+
+        let ledger = Ledger::open(&path).unwrap();
+        assert!(ledger.contains("foo-0.1.0"));
+        assert!(ledger.contains("bar-1.0.0"));
+        assert!(!ledger.contains("bar-1.0"));
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use colored::Color;
+use dumpsta::{
+    dialog::{Dialog, Sink},
+    disps,
+};
+use indicatif::ProgressBar;
+
+// A sink that can be read back after the dialogs are done writing to it
+#[derive(Clone, Default)]
+struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Capture {
+    fn sink(&self) -> Sink {
+        Arc::clone(&self.0) as Sink
+    }
+
+    fn output(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+fn capture() -> Capture {
+    colored::control::set_override(false);
+    Capture::default()
+}
+
+#[test]
+fn top_level_and_levels() {
+    let capture = capture();
+    let dialog = Dialog::new_with_sink(capture.sink(), "Scanning {} crates", disps![3]).unwrap();
+    dialog
+        .info_with("Found {} in {}", disps!["foo", Path::new("some/dir")])
+        .unwrap();
+    dialog.warn_with("Skipped {} of {}", disps![1, 3]).unwrap();
+    dialog.error("Something broke").unwrap();
+
+    assert_eq!(
+        capture.output(),
+        "Scanning 3 crates\n\
+         -> Found foo in some/dir\n\
+         -> Skipped 1 of 3\n\
+         -> Something broke\n"
+    );
+}
+
+#[test]
+fn sub_dialogs_are_indented() {
+    let capture = capture();
+    let dialog = Dialog::new_with_sink(capture.sink(), "Top", disps![]).unwrap();
+    let sub = dialog.info("One level").unwrap();
+    let sub_sub = sub.warn_with("Two levels {:>4}", disps![7]).unwrap();
+    sub_sub.info("Three levels").unwrap();
+    dialog.info("Back to one").unwrap();
+
+    assert_eq!(
+        capture.output(),
+        "Top\n\
+         -> One level\n\
+         \x20 -> Two levels    7\n\
+         \x20   -> Three levels\n\
+         -> Back to one\n"
+    );
+}
+
+#[test]
+fn multi_line_messages_line_up() {
+    let capture = capture();
+    let dialog = Dialog::new_with_sink(capture.sink(), "Top", disps![]).unwrap();
+    let sub = dialog.info("first\nsecond").unwrap();
+    sub.info_with("{}", disps!["third\nfourth"]).unwrap();
+
+    assert_eq!(
+        capture.output(),
+        "Top\n\
+         -> first\n   second\n\
+         \x20 -> third\n     fourth\n"
+    );
+}
+
+#[test]
+fn str_variants_only_return_the_line() {
+    let capture = capture();
+    let dialog = Dialog::new_with_sink(capture.sink(), "Top", disps![]).unwrap();
+    let (sub, line) = dialog
+        .msg_str_with(
+            Color::Green,
+            "Extracted {} ({} snapshots)",
+            disps!["foo", 2],
+        )
+        .unwrap();
+    assert_eq!(line, "-> Extracted foo (2 snapshots)");
+    let (_, line) = sub.warn_str_with("Nested {}", disps!["bar"]).unwrap();
+    assert_eq!(line, "  -> Nested bar");
+
+    assert_eq!(capture.output(), "Top\n");
+}
+
+#[test]
+fn bad_templates_write_nothing() {
+    let capture = capture();
+    let dialog = Dialog::new_with_sink(capture.sink(), "Top", disps![]).unwrap();
+    assert!(dialog.info_with("{} and {}", disps![1]).is_err());
+    assert!(dialog.info_with("{", disps![]).is_err());
+
+    assert_eq!(capture.output(), "Top\n");
+}
+
+#[test]
+fn hidden_progress_bars_fall_back_to_the_sink() {
+    let capture = capture();
+    let dialog = Dialog::new_with_sink(capture.sink(), "Top", disps![])
+        .unwrap()
+        .with_progress_bar(ProgressBar::hidden());
+    let sub = dialog.info("Beside the bar").unwrap();
+    sub.warn("Still nested").unwrap();
+
+    assert_eq!(
+        capture.output(),
+        "Top\n\
+         -> Beside the bar\n\
+         \x20 -> Still nested\n"
+    );
+}